@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use chrono::Utc;
+use uuid::Uuid;
+use arguments::Args;
+use crate::arguments;
+use crate::mysql_utils::json_to_string;
+use crate::schema::ColumnKind;
+use crate::state::StateStore;
+use crate::tables::AddColumn;
+use crate::timezone;
+use crate::transform::{self, Expr};
+
+#[derive(Debug, Clone)]
+enum AddedValue {
+    Fixed(String),
+    Uuid,
+    Expression(Expr),
+}
+
+/// A column added to every row, after the exported columns.
+#[derive(Debug, Clone)]
+pub struct AddedColumn {
+    pub name: String,
+    pub kind: ColumnKind,
+    value: AddedValue,
+}
+
+/// The start of the run in `--destination-timezone`, or UTC.
+fn run_timestamp(args: &Args, state: &StateStore) -> String {
+    state.run_timestamp(|| {
+        let now = Utc::now();
+        let local = match &args.destination_timezone {
+            Some(zone) => now.with_timezone(&timezone::parse_zone(zone)).naive_local(),
+            None => now.naive_utc(),
+        };
+        local.format("%Y-%m-%d %H:%M:%S").to_string()
+    })
+}
+
+/// Kind of the values an expression gives, so numeric results are written
+/// unquoted. `kinds` are those of the exported columns.
+fn expression_kind(expr: &Expr, kinds: &[ColumnKind]) -> ColumnKind {
+    match expr {
+        Expr::Number(_) | Expr::Negate(_) | Expr::Binary(..) => ColumnKind::Numeric,
+        Expr::Column(position) => kinds[*position],
+        Expr::Call(name, _) if name == "LENGTH" || name == "ROUND" => ColumnKind::Numeric,
+        Expr::Call(name, args) if name == "COALESCE" || name == "IFNULL" => {
+            let arg_kinds: Vec<ColumnKind> = args
+                .iter()
+                .filter(|arg| !matches!(arg, Expr::Null))
+                .map(|arg| expression_kind(arg, kinds))
+                .collect();
+            match arg_kinds.first() {
+                Some(kind) if arg_kinds.iter().all(|other| other == kind) => *kind,
+                _ => ColumnKind::Text,
+            }
+        }
+        _ => ColumnKind::Text,
+    }
+}
+
+/// Resolves the `add_columns` of a table once, before rows are read, in
+/// the order of their names.
+pub fn get_added_columns(
+    args: &Args,
+    state: &StateStore,
+    table: &str,
+    columns: &[String],
+    kinds: &[ColumnKind],
+    add_columns: &Option<HashMap<String, AddColumn>>,
+) -> Vec<AddedColumn> {
+    let mut added: Vec<AddedColumn> = Vec::new();
+    for (name, column) in add_columns.iter().flatten() {
+        if columns.contains(name) {
+            panic!("{}: added column {} is already exported", table, name);
+        }
+        let (kind, value) = match column {
+            AddColumn::Constant { value } if value.is_number() || value.is_boolean() => {
+                (ColumnKind::Numeric, AddedValue::Fixed(json_to_string(value)))
+            }
+            AddColumn::Constant { value } => (ColumnKind::Text, AddedValue::Fixed(json_to_string(value))),
+            AddColumn::Now => (ColumnKind::Text, AddedValue::Fixed(run_timestamp(args, state))),
+            AddColumn::Uuid => (ColumnKind::Text, AddedValue::Uuid),
+            AddColumn::Expression { expression } => {
+                let expr = transform::parse(expression, columns)
+                    .unwrap_or_else(|err| panic!("{}.{}: {}", table, name, err));
+                (expression_kind(&expr, kinds), AddedValue::Expression(expr))
+            }
+        };
+        added.push(AddedColumn { name: name.clone(), kind, value });
+    }
+    added.sort_by(|a, b| a.name.cmp(&b.name));
+    added
+}
+
+/// Appends the added columns to a row. Expressions read the exported
+/// columns as they are written, after overrides and masks.
+pub fn apply(values: &mut Vec<String>, added: &[AddedColumn]) {
+    let exported = values.len();
+    for column in added {
+        let value = match &column.value {
+            AddedValue::Fixed(value) => value.clone(),
+            AddedValue::Uuid => Uuid::new_v4().to_string(),
+            AddedValue::Expression(expr) => transform::evaluate(expr, &values[..exported])
+                .unwrap_or_else(|| "NULL".to_string()),
+        };
+        values.push(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind(expression: &str) -> ColumnKind {
+        let columns = vec!["id".to_string(), "name".to_string()];
+        let expr = transform::parse(expression, &columns).unwrap();
+        expression_kind(&expr, &[ColumnKind::Numeric, ColumnKind::Text])
+    }
+
+    #[test]
+    fn infers_expression_kinds() {
+        assert_eq!(kind("id * 10 + 1"), ColumnKind::Numeric);
+        assert_eq!(kind("id"), ColumnKind::Numeric);
+        assert_eq!(kind("LENGTH(name)"), ColumnKind::Numeric);
+        assert_eq!(kind("COALESCE(id, NULL, 0)"), ColumnKind::Numeric);
+        assert_eq!(kind("COALESCE(id, name)"), ColumnKind::Text);
+        assert_eq!(kind("'7'"), ColumnKind::Text);
+        assert_eq!(kind("CONCAT(id, '-', name)"), ColumnKind::Text);
+    }
+}
@@ -1,4 +1,8 @@
 use clap::Parser;
+use crate::discovery::TablePattern;
+use crate::remap::Unmapped;
+use crate::table_import::Transaction;
+use crate::timezone::{self, Ambiguity};
 /// This struct represents the command-line arguments for the program.
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -27,12 +31,81 @@ pub struct Args {
     /// Limit the number of rows in extended insert statements
     #[arg(long, default_value_t = 50)]
     pub extended_insert_limit: usize,
+    /// Compare numeric columns between source and destination after import
+    #[arg(long)]
+    pub verify_numeric: bool,
+    /// Continue an interrupted run from its last checkpoints. Imports of a
+    /// run started with it record each checkpoint in the destination's
+    /// `_migration_progress` table, in the transaction of its statements, so
+    /// resuming them again runs no statement twice
+    #[arg(long, conflicts_with = "clean")]
+    pub resume: bool,
+    /// Rows exported, or statements imported without `--transaction`, between checkpoints
+    #[arg(long, default_value_t = 10000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub checkpoint_rows: u64,
+    /// Import in transactions of `--transaction-size` statements or rows, or one per table
+    #[arg(long, value_enum)]
+    pub transaction: Option<Transaction>,
+    /// Statements or rows committed in each import transaction
+    #[arg(long, default_value_t = 1000, requires = "transaction", value_parser = clap::value_parser!(u64).range(1..))]
+    pub transaction_size: u64,
     /// Clean previous exports
     #[arg(long)]
     pub clean: bool,
-    /// Export a certain table
+    /// Limit the run to these tables (comma-separated)
     #[arg(long)]
     pub table: Option<String>,
+    /// Migrate every base table in the source schema
+    #[arg(long)]
+    pub discover: bool,
+    /// Only discover tables matching this glob, or regex with a `regex:` prefix
+    #[arg(long, requires = "discover", value_parser = TablePattern::parse)]
+    pub include: Vec<TablePattern>,
+    /// Skip discovered tables matching this glob, or regex with a `regex:` prefix
+    #[arg(long, requires = "discover", value_parser = TablePattern::parse)]
+    pub exclude: Vec<TablePattern>,
+    /// Export only the rows of `subset_root` tables and the rows they reference
+    #[arg(long)]
+    pub subset: bool,
+    /// Also follow rows that reference the subset roots
+    #[arg(long, requires = "subset")]
+    pub subset_children: bool,
+    /// What a reference to a row without a remapped ID is written as
+    #[arg(long, value_enum, default_value_t = Unmapped::Keep)]
+    pub unmapped_references: Unmapped,
+    /// Secret that salts the values generated by `mask` rules
+    #[arg(long)]
+    pub mask_secret: Option<String>,
+    /// File whose contents are used as the mask secret
+    #[arg(long)]
+    pub mask_secret_file: Option<String>,
+    /// Secret that keys `pseudonymize` masks
+    #[arg(long)]
+    pub pseudonym_secret: Option<String>,
+    /// File whose contents are used as the pseudonymization secret
+    #[arg(long)]
+    pub pseudonym_secret_file: Option<String>,
+    /// Encrypt export files, prompting for a passphrase if none is given
+    #[arg(long)]
+    pub encrypt: bool,
+    /// Passphrase used to encrypt and decrypt export files
+    #[arg(long)]
+    pub encryption_passphrase: Option<String>,
+    /// File whose contents are used as the encryption key
+    #[arg(long)]
+    pub encryption_key_file: Option<String>,
+    /// IANA timezone that source DATETIME values are stored in
+    #[arg(long, value_parser = timezone::parse_zone_name)]
+    pub source_timezone: Option<String>,
+    /// IANA timezone to convert DATETIME values into
+    #[arg(long, value_parser = timezone::parse_zone_name)]
+    pub destination_timezone: Option<String>,
+    /// How to resolve local times that occur twice during a DST change
+    #[arg(long, value_enum, default_value_t = Ambiguity::Earliest)]
+    pub timezone_ambiguity: Ambiguity,
+    /// Repair UTF-8 text that was double-encoded through latin1/cp1252
+    #[arg(long)]
+    pub repair_mojibake: bool,
     /// Source Database Host/Hostname/IP Address
     #[arg(long)]
     pub source_host: Option<String>,
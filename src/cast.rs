@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use crate::encryption::{Encryption, LazyFile};
+use crate::mysql_utils::json_to_string;
+use crate::schema::ColumnKind;
+use crate::tables::{CastRule, CastType};
+
+const DATETIME_FORMATS: &[&str] = &["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"];
+
+/// A `cast` rule resolved against the exported columns.
+#[derive(Debug, Clone)]
+pub struct ColumnCast {
+    position: usize,
+    column: String,
+    rule: CastRule,
+}
+
+/// A value that could not be converted.
+#[derive(Debug)]
+pub struct CastError {
+    pub column: String,
+    pub value: String,
+    pub reason: String,
+}
+
+/// Values written as NULL per column, and rows rejected by strict rules,
+/// which are written to `<table>-cast-errors.txt`.
+pub struct CastReport {
+    file: LazyFile,
+    pub nullified: HashMap<String, usize>,
+    pub rejected: usize,
+}
+
+impl CastReport {
+    pub fn new(export_path: &Path, file_stem: &str, encryption: &Option<Arc<Encryption>>) -> Self {
+        CastReport {
+            file: LazyFile::new(export_path.join(format!("{}-cast-errors.txt", file_stem)), encryption),
+            nullified: HashMap::new(),
+            rejected: 0,
+        }
+    }
+
+    /// Records a rejected row, identified by its primary key or its values.
+    pub fn reject(&mut self, row: &str, errors: &[CastError]) {
+        self.rejected += 1;
+        for error in errors {
+            self.file.write_line(&format!("{}\t{}\t{:?}\t{}", row, error.column, error.value, error.reason));
+        }
+    }
+
+    pub fn print(&mut self, table: &str) {
+        for (column, count) in &self.nullified {
+            eprintln!("{}.{}: {} values could not be converted and were written as NULL", table, column, count);
+        }
+        if self.file.finish() {
+            eprintln!(
+                "{}: rejected {} rows with values that could not be converted, see {}",
+                table, self.rejected, self.file.path().display()
+            );
+        }
+    }
+}
+
+/// Resolves the `cast` rules of a table once, before rows are read. Every
+/// cast column must be exported.
+pub fn get_casts(
+    table: &str,
+    columns: &[String],
+    cast: &Option<HashMap<String, CastRule>>,
+) -> Vec<ColumnCast> {
+    let mut casts: Vec<ColumnCast> = Vec::new();
+    for (column, rule) in cast.iter().flatten() {
+        let position = columns
+            .iter()
+            .position(|col| col == column)
+            .unwrap_or_else(|| panic!("{}: cast column {} is not exported", table, column));
+        if let CastType::Decimal { precision: Some(precision), scale: Some(scale) } = rule.to {
+            if scale > precision {
+                panic!("{}.{}: decimal scale is larger than its precision", table, column);
+            }
+        }
+        casts.push(ColumnCast { position, column: column.clone(), rule: rule.clone() });
+    }
+    casts.sort_by_key(|cast| cast.position);
+    casts
+}
+
+/// Kinds of the exported columns once cast: numbers are written unquoted.
+pub fn get_kinds(kinds: &[ColumnKind], casts: &[ColumnCast]) -> Vec<ColumnKind> {
+    let mut kinds = kinds.to_vec();
+    for cast in casts {
+        kinds[cast.position] = match cast.rule.to {
+            CastType::Integer | CastType::Decimal { .. } => ColumnKind::Numeric,
+            _ => ColumnKind::Text,
+        };
+    }
+    kinds
+}
+
+/// Converts the cast columns of a row. A value a strict rule cannot
+/// convert rejects the row; otherwise it is written as NULL, or truncated
+/// for `text`.
+pub fn apply(values: &mut [String], casts: &[ColumnCast], report: &mut CastReport) -> Result<(), Vec<CastError>> {
+    let mut errors: Vec<CastError> = Vec::new();
+    for cast in casts {
+        let value = &values[cast.position];
+        if value == "NULL" {
+            continue;
+        }
+        match convert(value, &cast.rule) {
+            Ok(converted) => values[cast.position] = converted,
+            Err((reason, _)) if cast.rule.strict => {
+                errors.push(CastError { column: cast.column.clone(), value: value.clone(), reason });
+            }
+            Err((_, fallback)) => {
+                if fallback == "NULL" {
+                    *report.nullified.entry(cast.column.clone()).or_insert(0) += 1;
+                }
+                values[cast.position] = fallback;
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Converts one value, or returns why it cannot be converted along with
+/// the value to write instead outside strict mode.
+fn convert(value: &str, rule: &CastRule) -> Result<String, (String, String)> {
+    let mut value = value.to_string();
+    if let Some(mapped) = rule.map.as_ref().and_then(|map| map.get(&value)) {
+        value = json_to_string(mapped);
+        if value == "NULL" {
+            return Ok(value);
+        }
+    }
+    if let Some(strip) = &rule.strip {
+        value.retain(|c| !strip.contains(c));
+    }
+    let value = value.trim();
+    let fail = |reason: String| Err((reason, "NULL".to_string()));
+    match &rule.to {
+        CastType::Integer => match parse_decimal(value) {
+            Some((negative, integer, fraction)) if fraction.bytes().all(|b| b == b'0') => {
+                let integer = integer.trim_start_matches('0');
+                let integer = if integer.is_empty() { "0" } else { integer };
+                let number = format!("{}{}", if negative && integer != "0" { "-" } else { "" }, integer);
+                if number.parse::<i64>().is_ok() || number.parse::<u64>().is_ok() {
+                    Ok(number)
+                } else {
+                    fail("out of range for a 64-bit integer".to_string())
+                }
+            }
+            Some(_) => fail("not a whole number".to_string()),
+            None => fail("not a number".to_string()),
+        },
+        CastType::Decimal { precision, scale } => match parse_decimal(value) {
+            Some((negative, integer, fraction)) => {
+                let (integer, fraction) = round_decimal(&integer, &fraction, scale.map(|scale| scale as usize));
+                let integer = match integer.trim_start_matches('0') {
+                    "" => "0",
+                    integer => integer,
+                };
+                if let Some(precision) = precision {
+                    let allowed = *precision as usize - scale.unwrap_or(0) as usize;
+                    let digits = if integer == "0" { 0 } else { integer.len() };
+                    if digits > allowed {
+                        return fail(format!("more than {} digits before the decimal point", allowed));
+                    }
+                }
+                let zero = integer.bytes().chain(fraction.bytes()).all(|b| b == b'0');
+                let sign = if negative && !zero { "-" } else { "" };
+                if fraction.is_empty() {
+                    Ok(format!("{}{}", sign, integer))
+                } else {
+                    Ok(format!("{}{}.{}", sign, integer, fraction))
+                }
+            }
+            None => fail("not a number".to_string()),
+        },
+        CastType::Enum { values } => values
+            .iter()
+            .find(|member| member.eq_ignore_ascii_case(value))
+            .cloned()
+            .ok_or_else(|| ("not one of the enum values".to_string(), "NULL".to_string())),
+        CastType::Datetime { from } => parse_datetime(value, from.as_deref())
+            .map(|datetime| datetime.format("%Y-%m-%d %H:%M:%S%.f").to_string())
+            .ok_or_else(|| ("not a valid datetime".to_string(), "NULL".to_string())),
+        CastType::Date { from } => parse_datetime(value, from.as_deref())
+            .map(|datetime| datetime.format("%Y-%m-%d").to_string())
+            .ok_or_else(|| ("not a valid date".to_string(), "NULL".to_string())),
+        CastType::Text { max_length: Some(max_length) } if value.chars().count() > *max_length => Err((
+            format!("longer than {} characters", max_length),
+            value.chars().take(*max_length).collect(),
+        )),
+        CastType::Text { .. } => Ok(value.to_string()),
+    }
+}
+
+/// Splits a plain decimal number into its sign, integer digits and
+/// fraction digits.
+pub fn parse_decimal(value: &str) -> Option<(bool, String, String)> {
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    let valid = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+    if (integer.is_empty() && fraction.is_empty()) || !valid(integer) || !valid(fraction) {
+        return None;
+    }
+    let integer = if integer.is_empty() { "0" } else { integer };
+    Some((negative, integer.to_string(), fraction.to_string()))
+}
+
+/// Rounds the digits half away from zero to `scale` fraction digits.
+pub fn round_decimal(integer: &str, fraction: &str, scale: Option<usize>) -> (String, String) {
+    let scale = match scale {
+        Some(scale) => scale,
+        None => return (integer.to_string(), fraction.to_string()),
+    };
+    if fraction.len() <= scale {
+        return (integer.to_string(), format!("{:0<width$}", fraction, width = scale));
+    }
+    let round_up = fraction.as_bytes()[scale] >= b'5';
+    let mut digits: Vec<u8> = integer.bytes().chain(fraction[..scale].bytes()).collect();
+    if round_up {
+        let mut position = digits.len();
+        loop {
+            if position == 0 {
+                digits.insert(0, b'1');
+                break;
+            }
+            position -= 1;
+            if digits[position] == b'9' {
+                digits[position] = b'0';
+            } else {
+                digits[position] += 1;
+                break;
+            }
+        }
+    }
+    let split = digits.len() - scale;
+    let digits = String::from_utf8(digits).unwrap();
+    (digits[..split].to_string(), digits[split..].to_string())
+}
+
+fn parse_datetime(value: &str, from: Option<&str>) -> Option<NaiveDateTime> {
+    match from {
+        Some("epoch") => DateTime::from_timestamp(value.parse().ok()?, 0).map(|datetime| datetime.naive_utc()),
+        Some("epoch_ms") => DateTime::from_timestamp_millis(value.parse().ok()?).map(|datetime| datetime.naive_utc()),
+        Some(format) => NaiveDateTime::parse_from_str(value, format)
+            .ok()
+            .or_else(|| NaiveDate::parse_from_str(value, format).ok()?.and_hms_opt(0, 0, 0)),
+        None => DATETIME_FORMATS
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+            .or_else(|| NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?.and_hms_opt(0, 0, 0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(rule: serde_json::Value) -> CastRule {
+        serde_json::from_value(rule).unwrap()
+    }
+
+    fn pair(integer: &str, fraction: &str) -> (String, String) {
+        (integer.to_string(), fraction.to_string())
+    }
+
+    #[test]
+    fn parses_decimals() {
+        assert_eq!(parse_decimal("-12.50"), Some((true, "12".to_string(), "50".to_string())));
+        assert_eq!(parse_decimal("+.5"), Some((false, "0".to_string(), "5".to_string())));
+        assert_eq!(parse_decimal("7"), Some((false, "7".to_string(), String::new())));
+        assert_eq!(parse_decimal("."), None);
+        assert_eq!(parse_decimal("1e5"), None);
+        assert_eq!(parse_decimal("1.2.3"), None);
+    }
+
+    #[test]
+    fn rounds_half_away_from_zero() {
+        assert_eq!(round_decimal("1", "245", Some(2)), pair("1", "25"));
+        assert_eq!(round_decimal("1", "244", Some(2)), pair("1", "24"));
+        assert_eq!(round_decimal("9", "995", Some(2)), pair("10", "00"));
+        assert_eq!(round_decimal("0", "5", Some(0)), pair("1", ""));
+        assert_eq!(round_decimal("3", "1", Some(3)), pair("3", "100"));
+        assert_eq!(round_decimal("3", "14159", None), pair("3", "14159"));
+    }
+
+    #[test]
+    fn converts_numbers() {
+        let integer = rule(serde_json::json!({"to": "integer", "strip": "$,"}));
+        assert_eq!(convert("$1,200.00", &integer), Ok("1200".to_string()));
+        assert_eq!(convert("-0", &integer), Ok("0".to_string()));
+        assert_eq!(convert("18446744073709551615", &integer), Ok("18446744073709551615".to_string()));
+        assert!(convert("1.5", &integer).is_err());
+        assert!(convert("18446744073709551616", &integer).is_err());
+
+        let decimal = rule(serde_json::json!({"to": "decimal", "precision": 5, "scale": 2}));
+        assert_eq!(convert("-0.001", &decimal), Ok("0.00".to_string()));
+        assert_eq!(convert("999.994", &decimal), Ok("999.99".to_string()));
+        assert_eq!(convert("999.995", &decimal).unwrap_err().0, "more than 3 digits before the decimal point");
+    }
+
+    #[test]
+    fn converts_other_types() {
+        let enumeration = rule(serde_json::json!({"to": "enum", "values": ["yes", "no"], "map": {"1": "yes", "x": null}}));
+        assert_eq!(convert("YES", &enumeration), Ok("yes".to_string()));
+        assert_eq!(convert("1", &enumeration), Ok("yes".to_string()));
+        assert_eq!(convert("x", &enumeration), Ok("NULL".to_string()));
+        assert_eq!(convert("maybe", &enumeration), Err(("not one of the enum values".to_string(), "NULL".to_string())));
+
+        let datetime = rule(serde_json::json!({"to": "datetime", "from": "epoch"}));
+        assert_eq!(convert("86400", &datetime), Ok("1970-01-02 00:00:00".to_string()));
+
+        let text = rule(serde_json::json!({"to": "text", "max_length": 3}));
+        assert_eq!(convert("abcd", &text), Err(("longer than 3 characters".to_string(), "abc".to_string())));
+    }
+}
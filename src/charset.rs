@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use encoding_rs::Encoding;
+use crate::schema::ColumnInfo;
+
+/// How the raw bytes of one column are turned into UTF-8 text.
+#[derive(Debug, Clone, Copy)]
+pub struct ColumnCharset {
+    pub encoding: &'static Encoding,
+    pub repair_mojibake: bool,
+}
+
+impl Default for ColumnCharset {
+    fn default() -> Self {
+        Self {
+            encoding: encoding_rs::UTF_8,
+            repair_mojibake: false,
+        }
+    }
+}
+
+/// Source sessions receive column values exactly as stored, so each column
+/// can be decoded with its own character set.
+pub fn source_session_init() -> Vec<String> {
+    vec![
+        "SET NAMES utf8mb4".to_string(),
+        "SET character_set_results = NULL".to_string(),
+    ]
+}
+
+/// Export files are always written as UTF-8.
+pub fn destination_session_init() -> Vec<String> {
+    vec!["SET NAMES utf8mb4".to_string()]
+}
+
+/// Maps a MySQL character set name to the matching WHATWG encoding.
+fn encoding_for(charset: &str) -> Option<&'static Encoding> {
+    let label = match charset {
+        "utf8" | "utf8mb3" | "utf8mb4" => "utf-8",
+        // MySQL's latin1 is really cp1252, and ascii is a subset of it.
+        "latin1" | "ascii" | "cp1252" => "windows-1252",
+        "latin2" => "iso-8859-2",
+        "latin5" => "windows-1254",
+        "latin7" => "iso-8859-13",
+        "greek" => "iso-8859-7",
+        "hebrew" => "iso-8859-8",
+        "cp1250" => "windows-1250",
+        "cp1251" => "windows-1251",
+        "cp1256" => "windows-1256",
+        "cp1257" => "windows-1257",
+        "cp866" => "ibm866",
+        "koi8r" => "koi8-r",
+        "koi8u" => "koi8-u",
+        "macroman" => "macintosh",
+        "tis620" => "windows-874",
+        "sjis" | "cp932" => "shift_jis",
+        "ujis" | "eucjpms" => "euc-jp",
+        "euckr" => "euc-kr",
+        "gb2312" | "gbk" => "gbk",
+        "gb18030" => "gb18030",
+        "big5" => "big5",
+        "ucs2" | "utf16" => "utf-16be",
+        "utf16le" => "utf-16le",
+        _ => return None,
+    };
+    Encoding::for_label(label.as_bytes())
+}
+
+/// Resolves the decoding of each exported column, by position.
+pub fn get_column_charsets(
+    table: &str,
+    columns: &[String],
+    column_info: &HashMap<String, ColumnInfo>,
+    repair_mojibake: bool,
+) -> Vec<ColumnCharset> {
+    columns.iter().map(|column| {
+        let charset = column_info
+            .get(column)
+            .and_then(|info| info.character_set.as_deref());
+        match charset {
+            Some(charset) => {
+                let encoding = encoding_for(charset).unwrap_or_else(|| {
+                    eprintln!(
+                        "{}.{}: unsupported character set {}, decoding as UTF-8",
+                        table, column, charset
+                    );
+                    encoding_rs::UTF_8
+                });
+                ColumnCharset { encoding, repair_mojibake }
+            }
+            None => ColumnCharset::default(),
+        }
+    }).collect()
+}
+
+pub fn decode(bytes: &[u8], charset: &ColumnCharset) -> String {
+    let (text, _) = charset.encoding.decode_without_bom_handling(bytes);
+    if charset.repair_mojibake {
+        repair_double_encoding(text.into_owned())
+    } else {
+        text.into_owned()
+    }
+}
+
+/// Undoes UTF-8 that was decoded as cp1252 and encoded as UTF-8 again,
+/// e.g. `CafÃ©` back to `Café`. Text is only changed when every character
+/// maps back to a single cp1252 byte and the bytes form valid UTF-8, and
+/// the repair is repeated for text that was encoded more than twice.
+fn repair_double_encoding(mut text: String) -> String {
+    for _ in 0..3 {
+        if text.is_ascii() {
+            break;
+        }
+        let (bytes, _, unmappable) = encoding_rs::WINDOWS_1252.encode(&text);
+        if unmappable {
+            break;
+        }
+        match String::from_utf8(bytes.into_owned()) {
+            Ok(repaired) if repaired != text => text = repaired,
+            _ => break,
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repairs_double_encoded_text() {
+        assert_eq!(repair_double_encoding("CafÃ©".to_string()), "Café");
+        assert_eq!(repair_double_encoding("â‚¬5 â€“ naÃ¯ve".to_string()), "€5 – naïve");
+        // Encoded three times.
+        assert_eq!(repair_double_encoding("CafÃƒÂ©".to_string()), "Café");
+    }
+
+    #[test]
+    fn keeps_valid_text() {
+        for text in ["Café", "naïve ½ €", "日本語", "Ünïcödé – ok", "plain"] {
+            assert_eq!(repair_double_encoding(text.to_string()), text);
+        }
+    }
+
+    #[test]
+    fn repairs_only_when_asked() {
+        let latin1 = ColumnCharset { encoding: encoding_for("latin1").unwrap(), repair_mojibake: true };
+        assert_eq!(decode(b"Caf\xc3\xa9", &latin1), "Café");
+        assert_eq!(decode(b"Caf\xe9", &latin1), "Café");
+        let plain = ColumnCharset { repair_mojibake: false, ..latin1 };
+        assert_eq!(decode(b"Caf\xc3\xa9", &plain), "CafÃ©");
+    }
+}
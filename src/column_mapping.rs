@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use crate::database::Database;
+use crate::schema;
+use crate::table_export;
+use crate::tables::TableConfig;
+
+type ColumnRename = Option<HashMap<String, String>>;
+
+/// Destination names of the exported columns after `column_rename`, and
+/// what is wrong with the renames. A union member may lack some of the
+/// union's renamed columns, which are then skipped.
+fn map_columns(
+    table: &TableConfig,
+    columns: &[String],
+    column_rename: &ColumnRename,
+) -> (Vec<String>, Vec<String>) {
+    let mut destination_columns = columns.to_vec();
+    let mut problems: Vec<String> = Vec::new();
+    let mut renames: Vec<(&String, &String)> = column_rename.iter().flatten().collect();
+    renames.sort();
+    for (column, target) in renames {
+        match columns.iter().position(|col| col == column) {
+            Some(position) => destination_columns[position] = target.clone(),
+            None if table.union_member => {}
+            None => problems.push(format!("{}: renamed column {} is not exported", table.name, column)),
+        }
+    }
+    for (index, column) in destination_columns.iter().enumerate() {
+        if destination_columns[..index].contains(column) {
+            problems.push(format!("{}: column {} is written twice after renaming", table.name, column));
+        }
+    }
+    (destination_columns, problems)
+}
+
+/// Resolves the destination names of a table's exported columns once,
+/// before rows are read.
+pub fn get_destination_columns(
+    table: &TableConfig,
+    columns: &[String],
+    column_rename: &ColumnRename,
+) -> Vec<String> {
+    let (destination_columns, problems) = map_columns(table, columns, column_rename);
+    if !problems.is_empty() {
+        panic!("{}", problems.join("\n"));
+    }
+    destination_columns
+}
+
+/// Checks every `column_rename` against the source columns and the
+/// destination's INFORMATION_SCHEMA before the run starts, printing each
+/// unknown column, and stops the run if there is any.
+pub fn validate(source: &Database, destination: &Database, tables: &[TableConfig]) {
+    let mut problems: Vec<String> = Vec::new();
+    let mut destination_info = HashMap::new();
+    for table in tables {
+        let mut renamed: Vec<(String, &[String], &ColumnRename)> = Vec::new();
+        let columns: Vec<String> = table_export::get_columns(source, table);
+        match &table.outputs {
+            Some(outputs) => {
+                for output in outputs.iter().filter(|output| output.column_rename.is_some()) {
+                    let selected = output.columns.as_deref().unwrap_or(&columns);
+                    renamed.push((output.name.clone(), selected, &output.column_rename));
+                }
+            }
+            None if table.column_rename.is_some() => {
+                let name = table.table_rename.clone().unwrap_or_else(|| table.name.clone());
+                renamed.push((name, &columns, &table.column_rename));
+            }
+            None => {}
+        }
+        for (name, selected, column_rename) in renamed {
+            let (_, mut table_problems) = map_columns(table, selected, column_rename);
+            let info = destination_info
+                .entry(name.clone())
+                .or_insert_with(|| schema::get_column_info(destination, &name));
+            if info.is_empty() {
+                table_problems.push(format!("Destination table {} of {} does not exist", name, table.name));
+            } else {
+                let mut targets: Vec<(&String, &String)> = column_rename.iter().flatten().collect();
+                targets.sort();
+                for (column, target) in targets {
+                    if !info.contains_key(target) {
+                        table_problems.push(format!(
+                            "{}.{}, renamed from {}, does not exist in the destination",
+                            name, target, column
+                        ));
+                    }
+                }
+            }
+            for problem in table_problems {
+                if !problems.contains(&problem) {
+                    problems.push(problem);
+                }
+            }
+        }
+    }
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+        panic!("Found {} problems with renamed columns", problems.len());
+    }
+}
@@ -12,10 +12,30 @@ use crate::arguments;
 
 #[derive(Debug, Deserialize)]
 pub struct ConnectionConfig {
-    pub source: ConnectionDatabaseConfig,
+    /// Databases migrated into the destination, in order
+    pub sources: Vec<SourceConfig>,
     pub destination: ConnectionDatabaseConfig,
 }
-#[derive(Debug, Serialize, Deserialize)]
+
+/// A constant column added to every row exported from a source.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Discriminator {
+    pub column: String,
+    pub value: Value,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SourceConfig {
+    /// Names the source in reports and its directory under the export path
+    pub name: String,
+    #[serde(flatten)]
+    pub connection: ConnectionDatabaseConfig,
+    pub discriminator: Option<Discriminator>,
+    /// Table configuration of this source, instead of `--table-config`
+    pub table_config: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ConnectionDatabaseConfig {
     pub hostname: String,
     pub port: u16,
@@ -36,10 +56,10 @@ pub fn get_config(args: &Args) -> ConnectionConfig {
     };
 
     let conn_map: &mut Map<String, Value> = conf.as_object_mut().unwrap();
-    process_connection_configuration(&args, conn_map);
+    process_connection_configuration(args, conn_map);
 
-    return serde_json::from_value(json!(conn_map))
-        .expect("Failed to deserialize ConnectionConfig");
+    serde_json::from_value(json!(conn_map))
+        .expect("Failed to deserialize ConnectionConfig")
 }
 pub fn process_connection_configuration(
     args: &Args,
@@ -51,8 +71,77 @@ pub fn process_connection_configuration(
     if !conn_map.contains_key("destination") {
         conn_map.insert("destination".to_string(), json!(Map::new()));
     }
-    process_source_configuration(&args, conn_map);
-    process_destination_configuration(&args, conn_map);
+    if conn_map.contains_key("sources") {
+        process_sources_configuration(args, conn_map);
+    } else {
+        process_source_configuration(args, conn_map);
+        let mut source = conn_map.remove("source").unwrap();
+        if let Some(map) = source.as_object_mut() {
+            let name = map.get("database").cloned().unwrap_or(json!("source"));
+            map.entry("name").or_insert(name);
+        }
+        conn_map.insert("sources".to_string(), json!([source]));
+    }
+    process_destination_configuration(args, conn_map);
+}
+
+/// Builds every entry of `sources` on top of `source` and the `--source-*`
+/// options, which hold the settings the sources share, and prompts for
+/// anything still missing.
+fn process_sources_configuration(args: &Args, conn_map: &mut Map<String, Value>) {
+    let mut defaults: Map<String, Value> = conn_map
+        .remove("source")
+        .and_then(|source| source.as_object().cloned())
+        .unwrap_or_default();
+    let options = [
+        ("hostname", args.source_host.as_ref().map(|host| json!(host))),
+        ("port", args.source_port.map(|port| json!(port))),
+        ("database", args.source_database.as_ref().map(|database| json!(database))),
+        ("username", args.source_username.as_ref().map(|username| json!(username))),
+        ("password", args.source_password.as_ref().map(|password| json!(password))),
+    ];
+    for (key, value) in options {
+        if let Some(value) = value {
+            defaults.insert(key.to_string(), value);
+        }
+    }
+    let entries = conn_map["sources"]
+        .as_array()
+        .expect("sources must be an array")
+        .clone();
+    if entries.is_empty() {
+        panic!("sources must list at least one source");
+    }
+    let mut sources: Vec<Value> = Vec::new();
+    for entry in entries {
+        let mut source = defaults.clone();
+        source.extend(entry.as_object().expect("Each source must be an object").clone());
+        let name = source
+            .get("name")
+            .or_else(|| source.get("database"))
+            .and_then(Value::as_str)
+            .expect("Each source needs a name or a database")
+            .to_string();
+        if sources.iter().any(|other| other["name"] == json!(name)) {
+            panic!("Source {} is listed twice", name);
+        }
+        source.insert("name".to_string(), json!(name));
+        let mut root = Map::new();
+        root.insert(name.clone(), json!(source));
+        for key in ["hostname", "database", "username"] {
+            if root[&name][key].is_null() {
+                input_string(name.clone(), key.to_string(), &mut root);
+            }
+        }
+        if root[&name]["port"].is_null() {
+            input_int(name.clone(), "port".to_string(), &mut root);
+        }
+        if root[&name]["password"].is_null() {
+            input_password(name.clone(), "password".to_string(), &mut root);
+        }
+        sources.push(root.remove(&name).unwrap());
+    }
+    conn_map.insert("sources".to_string(), json!(sources));
 }
 fn input_string(
     root_key: String,
@@ -1,12 +1,13 @@
 use std::sync::Arc;
-use mysql::Pool;
+use mysql::{Opts, OptsBuilder, Pool};
 use crate::connection::ConnectionDatabaseConfig;
 pub struct Database {
     pub pool: Arc<Pool>,
     pub name: String,
 }
 impl Database { 
-    pub fn new(conf: &ConnectionDatabaseConfig) -> Self {
+    /// Creates a pool whose connections run `init` statements when opened.
+    pub fn new(conf: &ConnectionDatabaseConfig, init: Vec<String>) -> Self {
         let url = format!(
             "mysql://{}:{}@{}:{}/{}",
             conf.username,
@@ -15,7 +16,9 @@ impl Database {
             conf.port,
             conf.database
         );
-        let pool: Pool = Pool::new(url.as_str()).unwrap();
+        let opts = OptsBuilder::from_opts(Opts::from_url(url.as_str()).unwrap())
+            .init(init);
+        let pool: Pool = Pool::new(opts).unwrap();
         let pool: Arc<Pool> = Arc::new(pool);
         Self {
             pool,
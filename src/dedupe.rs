@@ -0,0 +1,168 @@
+use std::path::Path;
+use std::sync::Arc;
+use mysql::Value as MySqlValue;
+use crate::charset::{self, ColumnCharset};
+use crate::encryption::{Encryption, LazyFile};
+use crate::tables::{DedupeKeep, TableConfig};
+
+/// Drops rows whose key repeats the last kept row's. Rows are read sorted
+/// by the key, so only that key is held in memory.
+pub struct Deduper {
+    positions: Vec<usize>,
+    /// Decoding of each key column, to lowercase it for `ignore_case`
+    charsets: Vec<ColumnCharset>,
+    ignore_case: bool,
+    previous: Option<Vec<MySqlValue>>,
+    /// Dropped rows, written to `<table>-duplicates.txt`
+    file: LazyFile,
+    dropped: usize,
+}
+
+/// Resolves the table's `dedupe` and returns it with the ORDER BY that
+/// puts each group of duplicates together, the row to keep first.
+pub fn get_deduper(
+    table: &TableConfig,
+    columns: &[String],
+    charsets: &[ColumnCharset],
+    key_columns: &[String],
+    export_path: &Path,
+    encryption: &Option<Arc<Encryption>>,
+) -> Option<(Deduper, Vec<String>)> {
+    let dedupe = table.dedupe.as_ref()?;
+    let positions: Vec<usize> = dedupe.key.iter().map(|column| {
+        columns
+            .iter()
+            .position(|col| col == column)
+            .unwrap_or_else(|| panic!("{}: dedupe key column {} is not exported", table.name, column))
+    }).collect();
+    // Sorting by the exact bytes, or by the lowercased value, keeps values
+    // that compare equal next to each other whatever the collation.
+    let mut order_by: Vec<String> = dedupe.key.iter().map(|column| {
+        if dedupe.ignore_case {
+            format!("CAST(LOWER({}) AS BINARY)", column)
+        } else {
+            format!("CAST({} AS BINARY)", column)
+        }
+    }).collect();
+    match dedupe.keep {
+        DedupeKeep::First => order_by.extend(key_columns.iter().cloned()),
+        DedupeKeep::Last => order_by.extend(key_columns.iter().map(|column| format!("{} DESC", column))),
+        DedupeKeep::Max => {
+            order_by.push(format!("{} DESC", dedupe.by.as_ref().unwrap()));
+            order_by.extend(key_columns.iter().cloned());
+        }
+    }
+    let deduper = Deduper {
+        charsets: positions.iter().map(|position| charsets[*position]).collect(),
+        positions,
+        ignore_case: dedupe.ignore_case,
+        previous: None,
+        file: LazyFile::new(export_path.join(format!("{}-duplicates.txt", table.file_stem())), encryption),
+        dropped: 0,
+    };
+    Some((deduper, order_by))
+}
+
+impl Deduper {
+    /// The row's key, decoded with each column's character set and
+    /// lowercased for `ignore_case`.
+    pub fn key(&self, raw: &[MySqlValue]) -> Vec<MySqlValue> {
+        self.positions.iter().zip(&self.charsets).map(|(position, charset)| match &raw[*position] {
+            MySqlValue::Bytes(bytes) if self.ignore_case => {
+                MySqlValue::Bytes(charset::decode(bytes, charset).to_lowercase().into_bytes())
+            }
+            value => value.clone(),
+        }).collect()
+    }
+
+    /// Whether a key is the same as the last kept row's. Keys with a NULL
+    /// are never duplicates, as in a unique index.
+    pub fn is_duplicate(&self, key: &[MySqlValue]) -> bool {
+        !key.contains(&MySqlValue::NULL) && self.previous.as_deref() == Some(key)
+    }
+
+    /// Records the key of a row that is exported. A row rejected by a
+    /// strict cast is not, so the next row of its group is kept instead.
+    pub fn keep(&mut self, key: Vec<MySqlValue>) {
+        self.previous = Some(key);
+    }
+
+    /// Records a dropped row, identified by its primary key or its values.
+    pub fn record(&mut self, values: &[String], row: &str) {
+        self.dropped += 1;
+        let key: Vec<&String> = self.positions.iter().map(|position| &values[*position]).collect();
+        self.file.write_line(&format!("{:?}\t{}", key, row));
+    }
+
+    pub fn print(&mut self, table: &str) {
+        if self.file.finish() {
+            println!("{}: dropped {} duplicate rows, see {}", table, self.dropped, self.file.path().display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deduper(ignore_case: bool, charset: ColumnCharset) -> Deduper {
+        Deduper {
+            positions: vec![1],
+            charsets: vec![charset],
+            ignore_case,
+            previous: None,
+            file: LazyFile::new(std::env::temp_dir().join("dedupe-test-duplicates.txt"), &None),
+            dropped: 0,
+        }
+    }
+
+    fn row(value: &[u8]) -> Vec<MySqlValue> {
+        vec![MySqlValue::Int(1), MySqlValue::Bytes(value.to_vec())]
+    }
+
+    fn latin1() -> ColumnCharset {
+        ColumnCharset { encoding: encoding_rs::WINDOWS_1252, repair_mojibake: false }
+    }
+
+    #[test]
+    fn keeps_distinct_latin1_values() {
+        let mut deduper = deduper(true, latin1());
+        // "café" and "cafè" in latin1
+        let first = deduper.key(&row(b"caf\xe9"));
+        deduper.keep(first);
+        assert!(!deduper.is_duplicate(&deduper.key(&row(b"caf\xe8"))));
+        assert!(deduper.is_duplicate(&deduper.key(&row(b"CAF\xc9"))));
+    }
+
+    #[test]
+    fn ignores_case_only_when_asked() {
+        let mut deduper = deduper(false, ColumnCharset::default());
+        let first = deduper.key(&row("Straße".as_bytes()));
+        deduper.keep(first);
+        assert!(!deduper.is_duplicate(&deduper.key(&row("straße".as_bytes()))));
+        assert!(deduper.is_duplicate(&deduper.key(&row("Straße".as_bytes()))));
+    }
+
+    #[test]
+    fn compares_with_the_last_kept_key() {
+        let mut deduper = deduper(true, ColumnCharset::default());
+        let first = deduper.key(&row(b"a"));
+        assert!(!deduper.is_duplicate(&first));
+        // A row that is not kept does not replace the previous key.
+        assert!(!deduper.is_duplicate(&deduper.key(&row(b"b"))));
+        deduper.keep(first);
+        assert!(deduper.is_duplicate(&deduper.key(&row(b"A"))));
+        let second = deduper.key(&row(b"b"));
+        deduper.keep(second);
+        assert!(!deduper.is_duplicate(&deduper.key(&row(b"a"))));
+    }
+
+    #[test]
+    fn never_matches_null_keys() {
+        let mut deduper = deduper(false, ColumnCharset::default());
+        let null = vec![MySqlValue::Int(1), MySqlValue::NULL];
+        let key = deduper.key(&null);
+        deduper.keep(key);
+        assert!(!deduper.is_duplicate(&deduper.key(&null)));
+    }
+}
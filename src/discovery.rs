@@ -0,0 +1,103 @@
+use glob::Pattern;
+use mysql::prelude::Queryable;
+use regex::Regex;
+use arguments::Args;
+use crate::arguments;
+use crate::database::Database;
+use crate::tables::TableConfig;
+
+/// A table name filter given to `--include` or `--exclude`.
+///
+/// Patterns are globs such as `log_*`, or regular expressions when prefixed
+/// with `regex:`.
+#[derive(Debug, Clone)]
+pub enum TablePattern {
+    Glob(Pattern),
+    Regex(Regex),
+}
+
+impl TablePattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        match pattern.strip_prefix("regex:") {
+            Some(expression) => Regex::new(&format!("^(?:{})$", expression))
+                .map(TablePattern::Regex)
+                .map_err(|err| format!("Invalid table regex {}: {}", expression, err)),
+            None => Pattern::new(pattern)
+                .map(TablePattern::Glob)
+                .map_err(|err| format!("Invalid table pattern {}: {}", pattern, err)),
+        }
+    }
+
+    pub fn matches(&self, table: &str) -> bool {
+        match self {
+            TablePattern::Glob(pattern) => pattern.matches(table),
+            TablePattern::Regex(regex) => regex.is_match(table),
+        }
+    }
+}
+
+pub fn query_base_tables(database: &Database) -> Vec<String> {
+    let query = "SELECT TABLE_NAME FROM INFORMATION_SCHEMA.TABLES WHERE TABLE_SCHEMA = ? AND TABLE_TYPE = 'BASE TABLE' ORDER BY TABLE_NAME";
+    let mut conn = database.pool.get_conn().unwrap();
+    conn.exec_map(query, (&database.name,), |table_name: String| table_name)
+        .unwrap()
+}
+
+/// Adds every base table of the source schema that passes `--include` and
+/// `--exclude` to the configured tables.
+///
+/// Tables listed in the table configuration keep their own settings and
+/// are never filtered out. Tables read by a `union` are left to it.
+pub fn discover_tables(
+    database: &Database,
+    args: &Args,
+    mut table_config: Vec<TableConfig>,
+) -> Vec<TableConfig> {
+    let discovered = query_base_tables(database);
+    let mut added = 0;
+    for table in discovered {
+        let included = args.include.is_empty()
+            || args.include.iter().any(|pattern| pattern.matches(&table));
+        let excluded = args.exclude.iter().any(|pattern| pattern.matches(&table));
+        let configured = table_config.iter().any(|config| {
+            config.name == table || config.union.iter().flatten().any(|pattern| {
+                TablePattern::parse(pattern).is_ok_and(|pattern| pattern.matches(&table))
+            })
+        });
+        if !included || excluded || configured {
+            continue;
+        }
+        table_config.push(TableConfig {
+            name: table,
+            ..Default::default()
+        });
+        added += 1;
+    }
+    println!("Discovered {} tables in {}", added, database.name);
+    table_config
+}
+
+/// Limits the run to the tables named by `--table`, in that order.
+///
+/// A named table without a configuration entry is migrated with the
+/// default settings.
+pub fn limit_tables(args: &Args, table_config: Vec<TableConfig>) -> Vec<TableConfig> {
+    let names: Vec<&str> = match &args.table {
+        Some(tables) => tables
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect(),
+        None => return table_config,
+    };
+    names.into_iter().map(|name| {
+        table_config
+            .iter()
+            .find(|config| config.name == name)
+            .cloned()
+            .unwrap_or_else(|| TableConfig {
+                name: name.to_string(),
+                ..Default::default()
+            })
+    }).collect()
+}
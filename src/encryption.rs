@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::Argon2;
+use hmac::{Hmac, Mac};
+use rpassword::read_password;
+use arguments::Args;
+use crate::arguments;
+use sha2::Sha256;
+
+/// Marks a file as written by the encrypting writer.
+const MAGIC: &[u8; 8] = b"MMENC001";
+const SALT_LEN: usize = 16;
+const NONCE_PREFIX_LEN: usize = 7;
+/// Plaintext bytes sealed per chunk.
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+/// Key material used to encrypt and decrypt export artifacts.
+///
+/// The run key is derived once with Argon2id from the passphrase or key
+/// file contents and a salt chosen for the run. Each file gets a fresh
+/// salt of its own, and its AES-256-GCM key is an HMAC-SHA256 of that salt
+/// under the run key.
+pub struct Encryption {
+    key_material: Vec<u8>,
+    /// Argon2id salt of the files written by this run
+    salt: [u8; SALT_LEN],
+    /// Keys already derived with Argon2id, by salt
+    keys: Mutex<HashMap<[u8; SALT_LEN], [u8; 32]>>,
+}
+
+impl Encryption {
+    pub fn new(key_material: Vec<u8>) -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Encryption {
+            key_material,
+            salt,
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Derives the key of a salt with Argon2id, once per salt.
+    fn run_key(&self, salt: &[u8; SALT_LEN]) -> io::Result<[u8; 32]> {
+        let mut keys = self.keys.lock().unwrap();
+        if let Some(key) = keys.get(salt) {
+            return Ok(*key);
+        }
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(&self.key_material, salt, &mut key)
+            .map_err(|err| io::Error::other(format!("Unable to derive encryption key: {}", err)))?;
+        keys.insert(*salt, key);
+        Ok(key)
+    }
+
+    /// The cipher of a file, keyed by the run key and the file's own salt.
+    fn cipher(&self, salt: &[u8; SALT_LEN], file_salt: &[u8; SALT_LEN]) -> io::Result<Aes256Gcm> {
+        let run_key = self.run_key(salt)?;
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&run_key).expect("HMAC accepts any key length");
+        mac.update(file_salt);
+        let key: [u8; 32] = mac.finalize().into_bytes().into();
+        Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+    }
+}
+
+pub fn get_encryption(args: &Args) -> Option<Arc<Encryption>> {
+    let key_material: Vec<u8> = if let Some(key_file) = &args.encryption_key_file {
+        fs::read(key_file).expect("Failed to read encryption key file")
+    } else if let Some(passphrase) = &args.encryption_passphrase {
+        passphrase.as_bytes().to_vec()
+    } else if args.encrypt {
+        println!("Please enter your encryption passphrase: ");
+        read_password().unwrap().trim().as_bytes().to_vec()
+    } else {
+        return None;
+    };
+    if key_material.is_empty() {
+        panic!("Encryption key material must not be empty");
+    }
+    Some(Arc::new(Encryption::new(key_material)))
+}
+
+/// A file opened by `create_file`.
+pub trait ArtifactWriter: Write + Send {
+    /// Writes out the rest of the file. An encrypted file only opens once
+    /// its final chunk has been sealed here, so a file that is not
+    /// finished reads as truncated.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+impl ArtifactWriter for File {
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+/// Creates a file for writing, sealing its contents when encryption is enabled.
+pub fn create_file(
+    path: &Path,
+    encryption: &Option<Arc<Encryption>>,
+) -> io::Result<Box<dyn ArtifactWriter>> {
+    let file: File = File::create(path)?;
+    match encryption {
+        Some(encryption) => Ok(Box::new(EncryptedWriter::new(file, encryption)?)),
+        None => Ok(Box::new(file)),
+    }
+}
+
+/// A report file that is only created once a line is written to it.
+pub struct LazyFile {
+    path: PathBuf,
+    encryption: Option<Arc<Encryption>>,
+    file: Option<Box<dyn ArtifactWriter>>,
+}
+
+impl LazyFile {
+    pub fn new(path: PathBuf, encryption: &Option<Arc<Encryption>>) -> Self {
+        LazyFile {
+            path,
+            encryption: encryption.clone(),
+            file: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write_line(&mut self, line: &str) {
+        if self.file.is_none() {
+            let file = create_file(&self.path, &self.encryption)
+                .unwrap_or_else(|_| panic!("Unable to create {}", self.path.display()));
+            self.file = Some(file);
+        }
+        let file = self.file.as_mut().unwrap();
+        writeln!(file, "{}", line)
+            .unwrap_or_else(|_| panic!("Unable to write to {}", self.path.display()));
+    }
+
+    /// Finishes the file, returning whether anything was written.
+    pub fn finish(&mut self) -> bool {
+        match self.file.as_mut() {
+            Some(file) => {
+                file.finish().unwrap_or_else(|_| panic!("Unable to write to {}", self.path.display()));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Opens a file for reading, decrypting it in memory when encryption is enabled.
+pub fn open_file(
+    path: &Path,
+    encryption: &Option<Arc<Encryption>>,
+) -> io::Result<Box<dyn Read + Send>> {
+    let file: File = File::open(path)?;
+    match encryption {
+        Some(encryption) => Ok(Box::new(DecryptedReader::new(file, encryption)?)),
+        None => Ok(Box::new(file)),
+    }
+}
+
+/// Builds the per-chunk nonce from the file prefix, the chunk counter and a
+/// final-chunk flag, so reordered, dropped or truncated chunks fail to open.
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_LEN], counter: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+pub struct EncryptedWriter<W: Write> {
+    inner: W,
+    cipher: Aes256Gcm,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u32,
+    buffer: Vec<u8>,
+    finished: bool,
+}
+
+impl<W: Write> EncryptedWriter<W> {
+    pub fn new(mut inner: W, encryption: &Encryption) -> io::Result<Self> {
+        let mut file_salt = [0u8; SALT_LEN];
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut file_salt);
+        OsRng.fill_bytes(&mut prefix);
+        let cipher = encryption.cipher(&encryption.salt, &file_salt)?;
+        inner.write_all(MAGIC)?;
+        inner.write_all(&encryption.salt)?;
+        inner.write_all(&file_salt)?;
+        inner.write_all(&prefix)?;
+        Ok(Self {
+            inner,
+            cipher,
+            prefix,
+            counter: 0,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            finished: false,
+        })
+    }
+
+    fn seal_chunk(&mut self, len: usize, last: bool) -> io::Result<()> {
+        let nonce = chunk_nonce(&self.prefix, self.counter, last);
+        let ciphertext = self.cipher
+            .encrypt(Nonce::from_slice(&nonce), &self.buffer[..len])
+            .map_err(|_| io::Error::other("Unable to encrypt chunk"))?;
+        self.inner.write_all(&[last as u8])?;
+        self.inner.write_all(&(ciphertext.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&ciphertext)?;
+        self.buffer.drain(..len);
+        self.counter = self.counter
+            .checked_add(1)
+            .ok_or_else(|| io::Error::other("Encrypted file exceeds the chunk limit"))?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> ArtifactWriter for EncryptedWriter<W> {
+    /// Seals the remaining buffer as the final chunk.
+    fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.seal_chunk(self.buffer.len(), true)?;
+        self.finished = true;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for EncryptedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::other("Encrypted file is already finished"));
+        }
+        self.buffer.extend_from_slice(buf);
+        // Keep at least one byte back so the final chunk is never empty
+        // unless the whole file is.
+        while self.buffer.len() > CHUNK_SIZE {
+            self.seal_chunk(CHUNK_SIZE, false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct DecryptedReader<R: Read> {
+    inner: R,
+    cipher: Aes256Gcm,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u32,
+    plaintext: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: Read> DecryptedReader<R> {
+    pub fn new(mut inner: R, encryption: &Encryption) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        let mut salt = [0u8; SALT_LEN];
+        let mut file_salt = [0u8; SALT_LEN];
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        inner.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "File is not encrypted"));
+        }
+        inner.read_exact(&mut salt)?;
+        inner.read_exact(&mut file_salt)?;
+        inner.read_exact(&mut prefix)?;
+        Ok(Self {
+            inner,
+            cipher: encryption.cipher(&salt, &file_salt)?,
+            prefix,
+            counter: 0,
+            plaintext: Vec::new(),
+            position: 0,
+            finished: false,
+        })
+    }
+
+    fn open_chunk(&mut self) -> io::Result<()> {
+        let mut header = [0u8; 5];
+        self.inner.read_exact(&mut header).map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Encrypted file is truncated",
+            ),
+            _ => err,
+        })?;
+        let last = match header[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid chunk header")),
+        };
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if len > CHUNK_SIZE + TAG_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid chunk length"));
+        }
+        let mut ciphertext = vec![0u8; len];
+        self.inner.read_exact(&mut ciphertext)?;
+        let nonce = chunk_nonce(&self.prefix, self.counter, last);
+        self.plaintext = self.cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unable to decrypt file: wrong key or corrupted data",
+            ))?;
+        self.position = 0;
+        self.counter = self.counter.wrapping_add(1);
+        self.finished = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position >= self.plaintext.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.open_chunk()?;
+        }
+        let available = &self.plaintext[self.position..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seal(encryption: &Encryption, plaintext: &[u8]) -> Vec<u8> {
+        let mut sealed: Vec<u8> = Vec::new();
+        let mut writer = EncryptedWriter::new(&mut sealed, encryption).unwrap();
+        writer.write_all(plaintext).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        sealed
+    }
+
+    fn open(encryption: &Encryption, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut plaintext: Vec<u8> = Vec::new();
+        DecryptedReader::new(sealed, encryption)?.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn round_trip() {
+        let encryption = Encryption::new(b"passphrase".to_vec());
+        for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE + 17] {
+            let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let sealed = seal(&encryption, &plaintext);
+            assert_eq!(open(&encryption, &sealed).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn files_have_their_own_key() {
+        let encryption = Encryption::new(b"passphrase".to_vec());
+        assert_ne!(seal(&encryption, b"same"), seal(&encryption, b"same"));
+        assert_eq!(encryption.keys.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn another_run_opens_the_file() {
+        let sealed = seal(&Encryption::new(b"passphrase".to_vec()), b"rows");
+        assert_eq!(open(&Encryption::new(b"passphrase".to_vec()), &sealed).unwrap(), b"rows");
+    }
+
+    #[test]
+    fn rejects_wrong_key() {
+        let sealed = seal(&Encryption::new(b"passphrase".to_vec()), b"rows");
+        assert!(open(&Encryption::new(b"other".to_vec()), &sealed).is_err());
+    }
+
+    #[test]
+    fn rejects_unfinished_files() {
+        let encryption = Encryption::new(b"passphrase".to_vec());
+        let mut sealed: Vec<u8> = Vec::new();
+        let mut writer = EncryptedWriter::new(&mut sealed, &encryption).unwrap();
+        writer.write_all(&vec![7u8; CHUNK_SIZE + 5]).unwrap();
+        drop(writer);
+        assert!(open(&encryption, &sealed).is_err());
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let encryption = Encryption::new(b"passphrase".to_vec());
+        let plaintext = vec![7u8; 2 * CHUNK_SIZE + 5];
+        let sealed = seal(&encryption, &plaintext);
+        let header = MAGIC.len() + 2 * SALT_LEN + NONCE_PREFIX_LEN;
+
+        let mut flipped = sealed.clone();
+        flipped[header + 10] ^= 1;
+        assert!(open(&encryption, &flipped).is_err());
+
+        let mut salt = sealed.clone();
+        salt[MAGIC.len() + SALT_LEN] ^= 1;
+        assert!(open(&encryption, &salt).is_err());
+
+        let chunk = 5 + CHUNK_SIZE + TAG_LEN;
+        assert!(open(&encryption, &sealed[..header + chunk]).is_err());
+        assert!(open(&encryption, &sealed[..sealed.len() - 1]).is_err());
+
+        // Swapping the first two chunks breaks their nonces.
+        let mut swapped = sealed[..header].to_vec();
+        swapped.extend_from_slice(&sealed[header + chunk..header + 2 * chunk]);
+        swapped.extend_from_slice(&sealed[header..header + chunk]);
+        swapped.extend_from_slice(&sealed[header + 2 * chunk..]);
+        assert!(open(&encryption, &swapped).is_err());
+    }
+}
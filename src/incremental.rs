@@ -0,0 +1,108 @@
+use mysql::prelude::Queryable;
+use crate::arguments::Args;
+use mysql::Value as MySqlValue;
+use crate::charset::ColumnCharset;
+use crate::database::Database;
+use crate::mysql_utils::value_to_string;
+use crate::state::{StateStore, Watermark};
+use crate::tables::TableConfig;
+
+/// Rows of one incremental run: above the stored watermark, up to the
+/// highest value present when the run started.
+#[derive(Debug, Clone)]
+pub struct WatermarkRange {
+    pub column: String,
+    pub after: Option<String>,
+    pub until: Option<String>,
+}
+
+impl WatermarkRange {
+    /// Condition appended to the export query, with its parameters.
+    pub fn condition(&self) -> (String, Vec<MySqlValue>) {
+        let until = match &self.until {
+            Some(until) => MySqlValue::from(until),
+            // An empty table or a new column: nothing to export yet.
+            None => return ("FALSE".to_string(), Vec::new()),
+        };
+        match &self.after {
+            Some(after) => (
+                format!("{} > ? AND {} <= ?", self.column, self.column),
+                vec![MySqlValue::from(after), until],
+            ),
+            None => (format!("{} <= ?", self.column), vec![until]),
+        }
+    }
+}
+
+/// Resolves the range to export for an incremental table.
+///
+/// Capping the range at the current maximum keeps rows written during the
+/// export for the next run instead of skipping them. Returns `Ok(None)` for
+/// tables without `incremental`.
+pub fn get_range(
+    database: &Database,
+    table: &TableConfig,
+    state: &StateStore,
+) -> Result<Option<WatermarkRange>, mysql::Error> {
+    let column = match &table.incremental {
+        Some(incremental) => incremental.column.clone(),
+        None => return Ok(None),
+    };
+    let after = match state.watermark(&table.name) {
+        Some(watermark) if watermark.column == column => Some(watermark.value),
+        Some(watermark) => {
+            println!(
+                "{}: incremental column changed from {} to {}, exporting all rows",
+                table.name, watermark.column, column
+            );
+            None
+        }
+        None => None,
+    };
+    let mut query = format!("SELECT MAX({}) FROM {}", column, table.name);
+    if let Some(cond) = &table.condition {
+        query.push_str(&format!(" WHERE {}", cond));
+    }
+    let mut conn = database.pool.get_conn()?;
+    let max: Option<MySqlValue> = conn.exec_first(query, ())?;
+    let until = match max {
+        Some(MySqlValue::NULL) | None => after.clone(),
+        Some(value) => Some(value_to_string(value, &ColumnCharset::default())),
+    };
+    Ok(Some(WatermarkRange { column, after, until }))
+}
+
+/// Records the end of an exported range. It becomes the next run's
+/// starting point once the table is imported without errors, or right away
+/// with `--export-only`; until then it is kept as pending.
+pub fn finish_range(args: &Args, state: &StateStore, table: &TableConfig, range: &WatermarkRange) {
+    if let Some(until) = &range.until {
+        if range.after.as_ref() != Some(until) {
+            let watermark = Watermark {
+                column: range.column.clone(),
+                value: until.clone(),
+            };
+            if args.export_only {
+                state.set_watermark(&table.name, watermark);
+            } else {
+                state.set_pending_watermark(&table.name, watermark);
+            }
+        }
+    }
+}
+
+/// Advances the watermark of an imported table, or keeps the previous one
+/// when the import had errors so the next run exports the range again.
+pub fn commit_range(state: &StateStore, table: &TableConfig, import_errors: i32) {
+    if table.incremental.is_none() {
+        return;
+    }
+    if import_errors == 0 {
+        state.commit_watermark(&table.name);
+    } else {
+        eprintln!(
+            "{}: import had errors, the next run exports the same rows again",
+            table.name
+        );
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use serde_json::Value;
+use crate::encryption::{Encryption, LazyFile};
+use crate::schema::ColumnKind;
+use crate::tables::{Override, OverrideKind};
+
+/// One step of a JSON path such as `$.address.phone` or `$.tags[0]`.
+#[derive(Debug, Clone, PartialEq)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/// `json_path` overrides of one column, with their paths parsed.
+#[derive(Debug, Clone)]
+pub struct JsonPathRewrite {
+    position: usize,
+    paths: Vec<(Vec<PathSegment>, Value)>,
+}
+
+/// Counts of JSON values that failed validation, per column, and rows
+/// rejected for them. Every invalid value is written to
+/// `<table>-json-errors.txt` so that none is lost.
+pub struct JsonReport {
+    file: LazyFile,
+    pub invalid: HashMap<String, usize>,
+    pub rejected: usize,
+}
+
+impl JsonReport {
+    pub fn new(export_path: &Path, file_stem: &str, encryption: &Option<Arc<Encryption>>) -> Self {
+        JsonReport {
+            file: LazyFile::new(export_path.join(format!("{}-json-errors.txt", file_stem)), encryption),
+            invalid: HashMap::new(),
+            rejected: 0,
+        }
+    }
+
+    /// Records the invalid values of a row, identified by its primary key
+    /// or its values.
+    pub fn record(&mut self, row: &str, invalid: &[(String, String)], rejected: bool) {
+        if rejected {
+            self.rejected += 1;
+        }
+        for (column, value) in invalid {
+            *self.invalid.entry(column.clone()).or_insert(0) += 1;
+            self.file.write_line(&format!("{}\t{}\t{:?}", row, column, value));
+        }
+    }
+
+    pub fn print(&mut self, table: &str) {
+        for (column, count) in &self.invalid {
+            eprintln!("{}.{}: found {} invalid JSON values", table, column, count);
+        }
+        if self.file.finish() {
+            if self.rejected > 0 {
+                eprintln!("{}: rejected {} rows with invalid JSON", table, self.rejected);
+            } else {
+                eprintln!("{}: exported the invalid JSON values as NULL", table);
+            }
+            eprintln!("{}: the original values are in {}", table, self.file.path().display());
+        }
+    }
+}
+
+fn parse_path(path: &str) -> Result<Vec<PathSegment>, String> {
+    let rest = path
+        .strip_prefix('$')
+        .ok_or_else(|| format!("JSON path {} must start with $", path))?;
+    let mut segments = Vec::new();
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '.' => {
+                let mut key = String::new();
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    for c in chars.by_ref() {
+                        if c == '"' {
+                            break;
+                        }
+                        key.push(c);
+                    }
+                } else {
+                    while let Some(&c) = chars.peek() {
+                        if c == '.' || c == '[' {
+                            break;
+                        }
+                        key.push(c);
+                        chars.next();
+                    }
+                }
+                if key.is_empty() {
+                    return Err(format!("JSON path {} has an empty key", path));
+                }
+                segments.push(PathSegment::Key(key));
+            }
+            '[' => {
+                let mut index = String::new();
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    index.push(c);
+                }
+                let index = index
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| format!("JSON path {} has an invalid array index", path))?;
+                segments.push(PathSegment::Index(index));
+            }
+            _ => return Err(format!("JSON path {} is not supported", path)),
+        }
+    }
+    Ok(segments)
+}
+
+/// Parses the `json_path` overrides of a table once, before rows are read.
+pub fn get_rewrites(
+    table: &str,
+    columns: &[String],
+    overrides: &Option<Vec<Override>>,
+) -> Vec<JsonPathRewrite> {
+    let mut rewrites: Vec<JsonPathRewrite> = Vec::new();
+    for o in overrides.iter().flatten() {
+        let name = match (&o.kind, &o.name) {
+            (OverrideKind::JsonPath, Some(name)) => name,
+            _ => continue,
+        };
+        let position = match columns.iter().position(|col| col == name) {
+            Some(position) => position,
+            None => {
+                eprintln!("{}: json_path override column {} is not exported", table, name);
+                continue;
+            }
+        };
+        let paths = o.set.iter().map(|(path, value)| {
+            let segments = parse_path(path)
+                .unwrap_or_else(|err| panic!("{}.{}: {}", table, name, err));
+            (segments, value.clone())
+        });
+        match rewrites.iter_mut().find(|rewrite| rewrite.position == position) {
+            Some(rewrite) => rewrite.paths.extend(paths),
+            None => rewrites.push(JsonPathRewrite {
+                position,
+                paths: paths.collect(),
+            }),
+        }
+    }
+    rewrites
+}
+
+fn skip_whitespace(document: &[u8], mut position: usize) -> usize {
+    while position < document.len() && document[position].is_ascii_whitespace() {
+        position += 1;
+    }
+    position
+}
+
+/// End of the value starting at `position` in a valid document.
+fn value_end(document: &[u8], mut position: usize) -> usize {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    while position < document.len() {
+        let c = document[position];
+        position += 1;
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == b'\\' {
+                escaped = true;
+            } else if c == b'"' {
+                in_string = false;
+                if depth == 0 {
+                    return position;
+                }
+            }
+            continue;
+        }
+        match c {
+            b'"' => in_string = true,
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' | b',' | b' ' | b'\t' | b'\n' | b'\r' if depth == 0 => return position - 1,
+            b'}' | b']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return position;
+                }
+            }
+            _ => {}
+        }
+    }
+    position
+}
+
+/// Byte range of the value at `path` in a valid document. The last of
+/// duplicate keys wins, as when the document is parsed.
+fn find_path(document: &str, path: &[PathSegment]) -> Option<Range<usize>> {
+    let bytes = document.as_bytes();
+    let mut start = skip_whitespace(bytes, 0);
+    for segment in path {
+        let (open, close) = match segment {
+            PathSegment::Key(_) => (b'{', b'}'),
+            PathSegment::Index(_) => (b'[', b']'),
+        };
+        if bytes.get(start) != Some(&open) {
+            return None;
+        }
+        let mut position = skip_whitespace(bytes, start + 1);
+        let mut found: Option<usize> = None;
+        let mut index = 0;
+        while position < bytes.len() && bytes[position] != close {
+            let member_start = position;
+            if let PathSegment::Key(key) = segment {
+                let key_end = value_end(bytes, position);
+                let name: String = serde_json::from_str(&document[position..key_end]).ok()?;
+                position = skip_whitespace(bytes, key_end);
+                position = skip_whitespace(bytes, position + 1);
+                if name == *key {
+                    found = Some(position);
+                }
+            } else if *segment == PathSegment::Index(index) {
+                found = Some(member_start);
+            }
+            index += 1;
+            position = skip_whitespace(bytes, value_end(bytes, position));
+            if bytes.get(position) == Some(&b',') {
+                position = skip_whitespace(bytes, position + 1);
+            }
+        }
+        start = found?;
+    }
+    Some(start..value_end(bytes, start))
+}
+
+/// Validates every JSON column of the row and applies `json_path` rewrites,
+/// returning the columns and values of invalid documents.
+///
+/// Invalid documents in JSON columns are exported as NULL rather than as a
+/// string that the destination would reject. Rewrites also apply to text
+/// columns holding JSON, which are left untouched if they fail to parse.
+/// A rewrite replaces the value at its path if it exists, like MySQL's
+/// `JSON_REPLACE`, and keeps the rest of the document as it was written,
+/// so numbers keep their full precision.
+pub fn process_row(
+    values: &mut [String],
+    columns: &[String],
+    kinds: &[ColumnKind],
+    rewrites: &[JsonPathRewrite],
+) -> Vec<(String, String)> {
+    let mut invalid: Vec<(String, String)> = Vec::new();
+    for (pos, kind) in kinds.iter().enumerate() {
+        let rewrite = rewrites.iter().find(|rewrite| rewrite.position == pos);
+        if (*kind != ColumnKind::Json && rewrite.is_none()) || values[pos] == "NULL" {
+            continue;
+        }
+        if serde_json::from_str::<serde::de::IgnoredAny>(&values[pos]).is_err() {
+            if *kind == ColumnKind::Json {
+                invalid.push((columns[pos].clone(), std::mem::replace(&mut values[pos], "NULL".to_string())));
+            }
+            continue;
+        }
+        if let Some(rewrite) = rewrite {
+            for (path, value) in &rewrite.paths {
+                if let Some(range) = find_path(&values[pos], path) {
+                    values[pos].replace_range(range, &value.to_string());
+                }
+            }
+        }
+    }
+    invalid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rewrite(document: &str, path: &str, value: Value) -> String {
+        let mut values = vec![document.to_string()];
+        let rewrites = vec![JsonPathRewrite { position: 0, paths: vec![(parse_path(path).unwrap(), value)] }];
+        assert!(process_row(&mut values, &["doc".to_string()], &[ColumnKind::Json], &rewrites).is_empty());
+        values.remove(0)
+    }
+
+    #[test]
+    fn parses_paths() {
+        assert_eq!(
+            parse_path("$.address.\"zip code\"[2]").unwrap(),
+            vec![PathSegment::Key("address".into()), PathSegment::Key("zip code".into()), PathSegment::Index(2)]
+        );
+        assert!(parse_path("address").is_err());
+        assert!(parse_path("$.a[x]").is_err());
+    }
+
+    #[test]
+    fn replaces_only_the_path() {
+        let document = r#"{"id": 123456789012345678901234567890, "price": 0.1000000000000000055511151231257827, "phone": "555", "tags": ["a", {"b": "}"}]}"#;
+        assert_eq!(
+            rewrite(document, "$.phone", json!(null)),
+            r#"{"id": 123456789012345678901234567890, "price": 0.1000000000000000055511151231257827, "phone": null, "tags": ["a", {"b": "}"}]}"#
+        );
+        assert_eq!(
+            rewrite(document, "$.tags[1].b", json!("x")),
+            r#"{"id": 123456789012345678901234567890, "price": 0.1000000000000000055511151231257827, "phone": "555", "tags": ["a", {"b": "x"}]}"#
+        );
+        assert_eq!(rewrite(r#"{"a":1,"a":2}"#, "$.a", json!(3)), r#"{"a":1,"a":3}"#);
+        assert_eq!(rewrite(r#"{"a\"b": 1, "c": 1}"#, "$.c", json!(2)), r#"{"a\"b": 1, "c": 2}"#);
+    }
+
+    #[test]
+    fn leaves_missing_paths() {
+        let document = r#"{"a": [1, 2]}"#;
+        assert_eq!(rewrite(document, "$.b", json!(0)), document);
+        assert_eq!(rewrite(document, "$.a[2]", json!(0)), document);
+        assert_eq!(rewrite(document, "$.a.b", json!(0)), document);
+    }
+
+    #[test]
+    fn returns_invalid_documents() {
+        let mut values = vec!["{broken".to_string(), "{broken".to_string()];
+        let columns = vec!["doc".to_string(), "text".to_string()];
+        let invalid = process_row(&mut values, &columns, &[ColumnKind::Json, ColumnKind::Text], &[]);
+        assert_eq!(invalid, vec![("doc".to_string(), "{broken".to_string())]);
+        assert_eq!(values, vec!["NULL".to_string(), "{broken".to_string()]);
+    }
+}
@@ -3,44 +3,220 @@ use std::io;
 use std::sync::Arc;
 use arguments::Args;
 use clap::Parser;
+use connection::{ConnectionDatabaseConfig, SourceConfig};
 use database::Database;
+use encryption::Encryption;
+use mask::MaskKeys;
+use remap::RemapPlan;
+use state::{ExportProgress, StateStore};
 
+mod add_columns;
 mod arguments;
+mod cast;
 mod connection;
+mod charset;
+mod column_mapping;
 mod database;
+mod dedupe;
+mod discovery;
+mod encryption;
 mod tables;
 mod table_export;
 mod table_import;
 mod export_path;
+mod incremental;
+mod json;
+mod mask;
 mod mysql_utils;
+mod numeric;
+mod overrides;
+mod pseudonym;
+mod remap;
+mod schema;
+mod script;
+mod spatial;
+mod state;
+mod subset;
+mod timezone;
+mod transform;
+mod union;
+mod verify;
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
     let args: Args = Args::parse();
     let conn_config: connection::ConnectionConfig = connection::get_config(&args);
-    let table_config: Vec<tables::TableConfig> = tables::get_config(&args);
+    let table_configs: Vec<Vec<tables::TableConfig>> = conn_config.sources
+        .iter()
+        .map(|source| tables::get_config(&args, source.table_config.as_deref()))
+        .collect();
+
+    let encryption: Option<Arc<Encryption>> = encryption::get_encryption(&args);
+    let mask_keys: Arc<MaskKeys> = mask::get_mask_keys(&args, &table_configs.concat());
 
     let export_path: Arc<std::path::PathBuf> = export_path::get_export_path(&args);
     export_path::create_export_dir(&args, &export_path);
-    let args = &Arc::new(args);
-    export(args, &conn_config, &table_config, export_path.clone()).await;
+    let multiple_sources = conn_config.sources.len() > 1;
+    let args = Arc::new(args);
+    let destination = Arc::new(conn_config.destination);
+    let mut reports: Vec<SourceReport> = Vec::new();
+    for (source, table_config) in conn_config.sources.into_iter().zip(table_configs) {
+        let name = source.name.clone();
+        // Each source keeps its files and state apart from the others.
+        let source_path = if multiple_sources {
+            println!("Migrating source {}", name);
+            Arc::new(export_path.join(&name))
+        } else {
+            export_path.clone()
+        };
+        let migration = tokio::spawn(migrate(
+            args.clone(),
+            source,
+            destination.clone(),
+            table_config,
+            source_path,
+            encryption.clone(),
+            mask_keys.clone()
+        ));
+        // A source that fails is reported and the next one still runs.
+        reports.push(migration.await.unwrap_or_else(|_| SourceReport {
+            name,
+            failed: true,
+            ..Default::default()
+        }));
+    }
 
-    if !args.export_only {
-        import(&conn_config, &table_config, export_path).await;
+    if multiple_sources {
+        println!("----------------------------------------------");
+        for report in &reports {
+            report.print();
+        }
+    }
+    if reports.iter().any(|report| report.failed) {
+        std::process::exit(1);
     }
     Ok(())
 }
 
+/// Outcome of one source, printed once every source has run.
+#[derive(Debug, Default)]
+struct SourceReport {
+    name: String,
+    failed: bool,
+    tables: usize,
+    exported: usize,
+    rows: u64,
+    import_errors: i32,
+}
+
+impl SourceReport {
+    fn print(&self) {
+        if self.failed {
+            eprintln!("{}: failed, see the error above", self.name);
+        } else {
+            println!(
+                "{}: exported {} of {} tables with {} rows, {} import errors",
+                self.name, self.exported, self.tables, self.rows, self.import_errors
+            );
+        }
+    }
+}
+
+/// Migrates one source: selects its tables, then exports, imports and
+/// verifies them.
+async fn migrate(
+    args: Arc<Args>,
+    source: SourceConfig,
+    destination: Arc<ConnectionDatabaseConfig>,
+    mut table_config: Vec<tables::TableConfig>,
+    export_path: Arc<std::path::PathBuf>,
+    encryption: Option<Arc<Encryption>>,
+    mask_keys: Arc<MaskKeys>
+) -> SourceReport {
+    if args.discover {
+        let source_db = Database::new(&source.connection, charset::destination_session_init());
+        table_config = discovery::discover_tables(&source_db, &args, table_config);
+    }
+    let mut table_config: Vec<tables::TableConfig> = discovery::limit_tables(&args, table_config);
+    if table_config.iter().any(|table| table.union.is_some()) {
+        let source_db = Database::new(&source.connection, charset::destination_session_init());
+        table_config = union::expand(&source_db, table_config);
+    }
+    if args.subset {
+        let source_db = Database::new(&source.connection, charset::destination_session_init());
+        table_config = subset::extract(&source_db, &args, table_config);
+    }
+    for table in table_config.iter_mut() {
+        table.discriminators.extend(source.discriminator.clone());
+    }
+
+    export_path::create_export_dir(&args, &export_path);
+    let state: Arc<StateStore> = Arc::new(StateStore::load(&export_path, &encryption));
+    if !args.resume {
+        state.reset_progress();
+    }
+    let renamed = |table: &tables::TableConfig| {
+        table.column_rename.is_some()
+            || table.outputs.iter().flatten().any(|output| output.column_rename.is_some())
+    };
+    if !args.export_only && table_config.iter().any(renamed) {
+        let source_db = Database::new(&source.connection, charset::destination_session_init());
+        let destination_db = Database::new(&destination, charset::destination_session_init());
+        column_mapping::validate(&source_db, &destination_db, &table_config);
+    }
+    let remap_plan: Arc<RemapPlan> = if table_config.iter().any(|table| table.remap.is_some()) {
+        let source_db = Database::new(&source.connection, charset::destination_session_init());
+        let destination_db = Database::new(&destination, charset::destination_session_init());
+        Arc::new(remap::plan(&source_db, &destination_db, &table_config, &state, &export_path, &encryption))
+    } else {
+        Arc::new(RemapPlan::default())
+    };
+    let export_failures = export(&args, &source.connection, &table_config, export_path.clone(), &encryption, &state, &mask_keys, &remap_plan).await;
+
+    let mut import_errors = 0;
+    if export_failures > 0 && !args.export_only {
+        // A failed table may have left part of its rows, or a file from an
+        // earlier run, in the export directory.
+        eprintln!("{}: skipping the import because {} tables failed to export", source.name, export_failures);
+    } else if !args.export_only {
+        import_errors = import(&args, &destination, &table_config, export_path, &encryption, &state).await;
+        if args.verify_numeric {
+            verify(&source.connection, &destination, &table_config);
+        }
+    }
+    let exports: Vec<ExportProgress> = table_config
+        .iter()
+        .filter_map(|table| state.export_progress(&table.name))
+        .filter(|progress| progress.completed)
+        .collect();
+    SourceReport {
+        name: source.name,
+        failed: export_failures > 0,
+        tables: table_config.len(),
+        exported: exports.len(),
+        rows: exports.iter().map(|progress| progress.rows).sum(),
+        import_errors,
+    }
+}
+
+/// Exports every table concurrently and returns how many of them failed.
+#[allow(clippy::too_many_arguments)]
 async fn export(
     args: &Arc<Args>,  // Changed to Arc<Args>
-    conn_config: &connection::ConnectionConfig,
-    table_config: &Vec<tables::TableConfig>,
-    export_path: Arc<std::path::PathBuf>
-) {
-    let source_db: Arc<Database> = Arc::new(Database::new(&conn_config.source));
+    source: &ConnectionDatabaseConfig,
+    table_config: &[tables::TableConfig],
+    export_path: Arc<std::path::PathBuf>,
+    encryption: &Option<Arc<Encryption>>,
+    state: &Arc<StateStore>,
+    mask_keys: &Arc<MaskKeys>,
+    remap_plan: &Arc<RemapPlan>
+) -> usize {
+    let mut init = charset::source_session_init();
+    init.extend(timezone::session_init(args, table_config));
+    let source_db: Arc<Database> = Arc::new(Database::new(source, init));
     let mut tasks = vec![];
 
-    for tbl in table_config.clone() {
+    for tbl in table_config.iter().cloned() {
         let database = source_db.clone();
         let table = Arc::new(tbl);
 
@@ -48,36 +224,88 @@ async fn export(
             Arc::clone(args),  // Pass cloned Arc<Args> 
             database,
             table,
-            export_path.clone()
+            export_path.clone(),
+            encryption.clone(),
+            state.clone(),
+            mask_keys.clone(),
+            remap_plan.clone()
         );
 
         tasks.push(task);
     }
 
-    future::join_all(tasks).await;
+    let results = future::join_all(tasks).await;
+    let mut failures = 0;
+    for (table, result) in table_config.iter().zip(results) {
+        match result {
+            Ok(true) => {}
+            // The error was printed by the task.
+            Ok(false) => failures += 1,
+            Err(err) => {
+                eprintln!("{}: export failed: {}", table.name, err);
+                failures += 1;
+            }
+        }
+    }
 
     println!("----------------------------------------------");
-    println!("EXPORT COMPLETE");
+    if failures > 0 {
+        println!("EXPORT COMPLETE, {} TABLES FAILED", failures);
+    } else {
+        println!("EXPORT COMPLETE");
+    }
     println!("----------------------------------------------");
+    failures
 }
 
 async fn import(
-    conn_config: &connection::ConnectionConfig,
-    table_config: &Vec<tables::TableConfig>,
-    export_path: Arc<std::path::PathBuf>
-) {
-    let destination_db = Arc::new(Database::new(&conn_config.destination));
-    for tbl in table_config.clone() {
+    args: &Arc<Args>,
+    destination: &ConnectionDatabaseConfig,
+    table_config: &[tables::TableConfig],
+    export_path: Arc<std::path::PathBuf>,
+    encryption: &Option<Arc<Encryption>>,
+    state: &Arc<StateStore>
+) -> i32 {
+    let mut init = charset::destination_session_init();
+    init.extend(timezone::session_init(args, table_config));
+    let destination_db = Arc::new(Database::new(destination, init));
+    let mut errors = 0;
+    for tbl in table_config.iter().cloned() {
         let database = destination_db.clone();
         let table = Arc::new(tbl);
-        table_import::import(
+        let table_errors = table_import::import(
+            args.clone(),
             database,
-            table,
-            export_path.clone()
+            table.clone(),
+            export_path.clone(),
+            encryption.clone(),
+            state.clone()
         ).await;
+        incremental::commit_range(state, &table, table_errors);
+        errors += table_errors;
+    }
+    if args.resume {
+        table_import::drop_progress_table(&destination_db);
     }
     
     println!("----------------------------------------------");
     println!("IMPORT COMPLETE");
     println!("----------------------------------------------");
+    errors
+}
+
+fn verify(
+    source: &ConnectionDatabaseConfig,
+    destination: &ConnectionDatabaseConfig,
+    table_config: &[tables::TableConfig]
+) {
+    let source_db = Database::new(source, charset::destination_session_init());
+    let destination_db = Database::new(destination, charset::destination_session_init());
+    for table in table_config {
+        verify::verify_numeric(&source_db, &destination_db, table);
+    }
+
+    println!("----------------------------------------------");
+    println!("VERIFICATION COMPLETE");
+    println!("----------------------------------------------");
 }
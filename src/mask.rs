@@ -0,0 +1,280 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use chrono::{Duration, NaiveDateTime};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use sha2::{Digest, Sha256};
+use arguments::Args;
+use crate::arguments;
+use crate::pseudonym::{self, NumericDomain, Pseudonymizer};
+use crate::schema::ColumnInfo;
+use crate::spatial::to_hex;
+use crate::tables::{GeneratedMask, MaskRule, TableConfig};
+
+const FIRST_NAMES: &[&str] = &[
+    "James", "Mary", "Robert", "Patricia", "John", "Jennifer", "Michael", "Linda",
+    "David", "Elizabeth", "William", "Barbara", "Richard", "Susan", "Joseph", "Jessica",
+    "Thomas", "Sarah", "Daniel", "Karen", "Matthew", "Nancy", "Anthony", "Lisa",
+];
+const LAST_NAMES: &[&str] = &[
+    "Smith", "Johnson", "Williams", "Brown", "Jones", "Garcia", "Miller", "Davis",
+    "Rodriguez", "Martinez", "Hernandez", "Lopez", "Wilson", "Anderson", "Thomas", "Taylor",
+    "Moore", "Jackson", "Martin", "Lee", "Thompson", "White", "Harris", "Clark",
+];
+const STREETS: &[&str] = &[
+    "Maple", "Oak", "Cedar", "Pine", "Elm", "Willow", "Birch", "Lake",
+    "Hill", "Park", "Sunset", "River", "Meadow", "Forest", "Spring", "Church",
+];
+const STREET_SUFFIXES: &[&str] = &["Street", "Avenue", "Road", "Lane", "Drive", "Court", "Way", "Place"];
+
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.6f";
+
+/// Attempts with a different hash before giving up on a unique value.
+const UNIQUE_ATTEMPTS: u32 = 64;
+
+/// Secrets the masks are keyed by, read once for the whole run and never
+/// written anywhere.
+#[derive(Default)]
+pub struct MaskKeys {
+    /// Salt of generated values, the mask secret
+    salt: Vec<u8>,
+    pseudonymizer: Option<Pseudonymizer>,
+}
+
+/// Reads the secrets that the tables' masks need, prompting for each one
+/// that no option gives.
+pub fn get_mask_keys(args: &Args, table_config: &[TableConfig]) -> Arc<MaskKeys> {
+    let keyed = table_config.iter().any(|table| {
+        table.mask.iter().flatten().any(|(_, rule)| matches!(rule, MaskRule::Generate(rule) if rule.is_keyed()))
+    });
+    let salt = if keyed {
+        pseudonym::read_secret(&args.mask_secret_file, &args.mask_secret, "mask")
+    } else {
+        Vec::new()
+    };
+    Arc::new(MaskKeys {
+        salt,
+        pseudonymizer: pseudonym::get_pseudonymizer(args, table_config),
+    })
+}
+
+/// A masking rule resolved against the exported columns.
+#[derive(Debug)]
+struct ColumnMask {
+    position: usize,
+    column: String,
+    rule: MaskRule,
+    /// Values written so far, kept for columns with a unique key
+    written: Option<HashSet<String>>,
+    /// Shuffled values of the column, for `shuffle`
+    pool: Vec<String>,
+    /// Numbers of a numeric pseudonym
+    domain: Option<NumericDomain>,
+}
+
+/// Applies the `mask` rules of a table to each exported row.
+///
+/// Generated values are derived from a hash of the original value salted
+/// with the mask secret, so equal inputs mask to equal outputs in every
+/// run with the same secret. `pseudonymize` is keyed by its own secret.
+pub struct Masker {
+    table: String,
+    keys: Arc<MaskKeys>,
+    masks: Vec<ColumnMask>,
+}
+
+impl Masker {
+    pub fn new(
+        table: &TableConfig,
+        columns: &[String],
+        column_info: &HashMap<String, ColumnInfo>,
+        keys: Arc<MaskKeys>,
+    ) -> Self {
+        let mut masks: Vec<ColumnMask> = Vec::new();
+        for (column, rule) in table.mask.iter().flatten() {
+            let position = match columns.iter().position(|col| col == column) {
+                Some(position) => position,
+                None => {
+                    eprintln!("{}: masked column {} is not exported", table.name, column);
+                    continue;
+                }
+            };
+            let unique = column_info
+                .get(column)
+                .is_some_and(|info| info.is_primary || info.is_unique);
+            masks.push(ColumnMask {
+                position,
+                column: column.clone(),
+                rule: rule.clone(),
+                written: if unique { Some(HashSet::new()) } else { None },
+                pool: Vec::new(),
+                domain: match rule {
+                    MaskRule::Pseudonymize(rule) => pseudonym::get_domain(&table.name, column, rule, column_info.get(column)),
+                    MaskRule::Generate(_) => None,
+                },
+            });
+        }
+        masks.sort_by_key(|mask| mask.position);
+        Self { table: table.name.clone(), keys, masks }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.masks.is_empty()
+    }
+
+    /// Whether masked values are checked against the ones already written,
+    /// which a resumed export no longer knows.
+    pub fn checks_unique(&self) -> bool {
+        self.masks.iter().any(|mask| mask.written.is_some())
+    }
+
+    /// Positions of the columns whose values have to be read up front.
+    pub fn shuffled_positions(&self) -> Vec<usize> {
+        self.masks
+            .iter()
+            .filter(|mask| matches!(mask.rule, MaskRule::Generate(GeneratedMask::Shuffle)))
+            .map(|mask| mask.position)
+            .collect()
+    }
+
+    /// Sets the values a shuffled column draws from. The order only depends
+    /// on the values and the salt, so a resumed export continues with the
+    /// same permutation.
+    pub fn set_pool(&mut self, table: &str, position: usize, mut values: Vec<String>) {
+        values.sort();
+        let mask = match self.masks.iter_mut().find(|mask| mask.position == position) {
+            Some(mask) => mask,
+            None => return,
+        };
+        let seed: [u8; 32] = Sha256::new()
+            .chain_update(&self.keys.salt)
+            .chain_update(table.as_bytes())
+            .chain_update([0])
+            .chain_update(mask.column.as_bytes())
+            .finalize()
+            .into();
+        values.shuffle(&mut StdRng::from_seed(seed));
+        mask.pool = values;
+    }
+
+    /// Masks the row in place. `row_index` is the position of the row in
+    /// the export, used to pick shuffled values.
+    pub fn apply(&mut self, values: &mut [String], row_index: u64) {
+        for mask in self.masks.iter_mut() {
+            let original = &values[mask.position];
+            if original == "NULL" {
+                continue;
+            }
+            let mut masked = match &mask.rule {
+                MaskRule::Generate(rule) => mask_value(&self.keys.salt, rule, original, &mask.pool, row_index, 0),
+                MaskRule::Pseudonymize(rule) => self.keys.pseudonymizer
+                    .as_ref()
+                    .expect("the pseudonymization secret is read when a table pseudonymizes")
+                    .pseudonymize(original, rule.format, rule.keep_domain, mask.domain.as_ref()),
+            };
+            if let Some(written) = mask.written.as_mut() {
+                let mut attempt = 1;
+                while masked != "NULL" && written.contains(&masked) {
+                    // Pseudonyms only repeat for repeated inputs, and must
+                    // not depend on the other values of the table.
+                    masked = match &mask.rule {
+                        MaskRule::Generate(rule) if attempt < UNIQUE_ATTEMPTS => {
+                            mask_value(&self.keys.salt, rule, original, &mask.pool, row_index, attempt)
+                        }
+                        _ => panic!(
+                            "{}: unable to mask {} with a value that is not already in the unique column after {} attempts",
+                            self.table, mask.column, attempt
+                        ),
+                    };
+                    attempt += 1;
+                }
+                written.insert(masked.clone());
+            }
+            values[mask.position] = masked;
+        }
+    }
+}
+
+fn digest(salt: &[u8], value: &str, attempt: u32) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(salt)
+        .chain_update(attempt.to_le_bytes())
+        .chain_update(value.as_bytes())
+        .finalize()
+        .into()
+}
+
+fn pick<'a>(list: &[&'a str], byte: u8) -> &'a str {
+    list[byte as usize % list.len()]
+}
+
+fn mask_value(
+    salt: &[u8],
+    rule: &GeneratedMask,
+    value: &str,
+    pool: &[String],
+    row_index: u64,
+    attempt: u32,
+) -> String {
+    let hash = digest(salt, value, attempt);
+    match rule {
+        GeneratedMask::FakeName => format!("{} {}", pick(FIRST_NAMES, hash[0]), pick(LAST_NAMES, hash[1])),
+        GeneratedMask::FakeEmail => format!(
+            "{}.{}{}@example.com",
+            pick(FIRST_NAMES, hash[0]).to_lowercase(),
+            pick(LAST_NAMES, hash[1]).to_lowercase(),
+            u16::from_le_bytes([hash[2], hash[3]]) % 10000
+        ),
+        GeneratedMask::FakePhone => fake_phone(value, &hash),
+        GeneratedMask::FakeAddress => format!(
+            "{} {} {}",
+            u16::from_le_bytes([hash[2], hash[3]]) % 9899 + 100,
+            pick(STREETS, hash[0]),
+            pick(STREET_SUFFIXES, hash[1])
+        ),
+        GeneratedMask::Redact { with } => with.clone().unwrap_or_else(|| "REDACTED".to_string()),
+        GeneratedMask::Partial { keep_start, keep_end, mask_char } => {
+            let chars: Vec<char> = value.chars().collect();
+            let keep_end = (*keep_end).min(chars.len());
+            chars.iter().enumerate().map(|(index, c)| {
+                if index < *keep_start || index >= chars.len() - keep_end {
+                    *c
+                } else {
+                    mask_char.unwrap_or('*')
+                }
+            }).collect()
+        }
+        GeneratedMask::Nullify => "NULL".to_string(),
+        GeneratedMask::Hash => to_hex(&hash).to_lowercase(),
+        GeneratedMask::Shuffle if pool.is_empty() => "NULL".to_string(),
+        // Rows added after the pool was read reuse its values.
+        GeneratedMask::Shuffle => pool[((row_index + attempt as u64) % pool.len() as u64) as usize].clone(),
+        GeneratedMask::DateJitter { days } => jitter_date(value, *days, &hash),
+    }
+}
+
+/// Replaces every digit, keeping the separators and length of the number.
+fn fake_phone(value: &str, hash: &[u8; 32]) -> String {
+    let mut digits = hash.iter().cycle().skip(4);
+    if !value.chars().any(|c| c.is_ascii_digit()) {
+        return format!("555-{:07}", u32::from_le_bytes([hash[4], hash[5], hash[6], hash[7]]) % 10_000_000);
+    }
+    value.chars().map(|c| {
+        if c.is_ascii_digit() {
+            char::from(b'0' + digits.next().unwrap() % 10)
+        } else {
+            c
+        }
+    }).collect()
+}
+
+fn jitter_date(value: &str, days: i64, hash: &[u8; 32]) -> String {
+    let date = match NaiveDateTime::parse_from_str(value, DATE_FORMAT) {
+        Ok(date) => date,
+        Err(_) => return value.to_string(),
+    };
+    let span = (days.abs() * 2 + 1) as u64;
+    let offset = (u64::from_le_bytes(hash[..8].try_into().unwrap()) % span) as i64 - days.abs();
+    (date + Duration::days(offset)).format(DATE_FORMAT).to_string()
+}
@@ -1,14 +1,17 @@
 use mysql::Value as MySqlValue;
-use mysql::Row;
+use crate::charset::{self, ColumnCharset};
+use crate::numeric;
+use crate::schema::ColumnKind;
+use crate::spatial;
 
-pub fn value_to_string(value: MySqlValue) -> String {
+pub fn value_to_string(value: MySqlValue, charset: &ColumnCharset) -> String {
     match value {
         MySqlValue::NULL => "NULL".to_string(),
-        MySqlValue::Bytes(bytes) => escape_string(&bytes),
+        MySqlValue::Bytes(bytes) => charset::decode(&bytes, charset),
         MySqlValue::Int(int) => int.to_string(),
         MySqlValue::UInt(uint) => uint.to_string(),
-        MySqlValue::Float(float) => float.to_string(),
-        MySqlValue::Double(double) => double.to_string(),
+        MySqlValue::Float(float) => numeric::format_f32(float),
+        MySqlValue::Double(double) => numeric::format_f64(double),
         MySqlValue::Date(year, month, day, hour, minute, second, micro) => {
             if year == 0 && month == 0 && day == 0 && hour == 0 && minute == 0 && second == 0 && micro == 0 {
                 "NULL".to_string()
@@ -16,12 +19,22 @@ pub fn value_to_string(value: MySqlValue) -> String {
                 format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06}", year, month, day, hour, minute, second, micro)
             }
         },
-        _ => "NULL".to_string(),
+        MySqlValue::Time(negative, days, hours, minutes, seconds, micro) => {
+            format!(
+                "{}{:02}:{:02}:{:02}.{:06}",
+                if negative { "-" } else { "" },
+                days * 24 + hours as u32,
+                minutes,
+                seconds,
+                micro
+            )
+        },
     }
 }
 
-fn escape_string(bytes: &[u8]) -> String {
-    let mut escaped_string = String::from_utf8_lossy(bytes).to_string();
+/// Escapes a value for use inside a double-quoted string literal.
+pub fn escape_string(value: &str) -> String {
+    let mut escaped_string = value.to_string();
     escaped_string = escaped_string.replace("\\", "\\\\");
     escaped_string = escaped_string.replace("\"", "\\\"");
     escaped_string = escaped_string.replace("\n", "\\n");
@@ -30,9 +43,46 @@ fn escape_string(bytes: &[u8]) -> String {
     escaped_string
 }
 
-pub fn to_vector_string(row: Row) -> Vec<String> {
-    row.unwrap()
+/// Renders a value as a single-quoted utf8mb4 string literal.
+pub fn quote_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 11);
+    quoted.push_str("_utf8mb4'");
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '\'' => quoted.push_str("\\'"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\0' => quoted.push_str("\\0"),
+            '\x1a' => quoted.push_str("\\Z"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// Converts a JSON value from the configuration into a column value.
+pub fn json_to_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => "NULL".to_string(),
+        serde_json::Value::String(string) => string.clone(),
+        serde_json::Value::Bool(boolean) => (*boolean as u8).to_string(),
+        other => other.to_string(),
+    }
+}
+
+pub fn to_vector_string(
+    values: Vec<MySqlValue>,
+    charsets: &[ColumnCharset],
+    kinds: &[ColumnKind],
+) -> Vec<String> {
+    values
         .into_iter()
-        .map(value_to_string)
+        .zip(charsets.iter().zip(kinds))
+        .map(|(value, (charset, kind))| match (value, kind) {
+            (MySqlValue::Bytes(bytes), ColumnKind::Spatial) => spatial::to_hex(&bytes),
+            (value, _) => value_to_string(value, charset),
+        })
         .collect()
 }
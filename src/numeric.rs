@@ -0,0 +1,47 @@
+use std::fmt::{Display, LowerExp};
+
+/// Numeric data types reported by INFORMATION_SCHEMA.COLUMNS.
+pub const NUMERIC_TYPES: [&str; 11] = [
+    "tinyint",
+    "smallint",
+    "mediumint",
+    "int",
+    "integer",
+    "bigint",
+    "decimal",
+    "numeric",
+    "float",
+    "double",
+    "real",
+];
+
+/// Formats a float with the shortest digits that parse back to the same
+/// value, switching to exponent form for very large or small magnitudes.
+///
+/// MySQL has no literal for NaN or infinity, so those become NULL.
+fn format_float<T: Display + LowerExp>(value: T, finite: bool, magnitude: f64) -> String {
+    if !finite {
+        "NULL".to_string()
+    } else if magnitude != 0.0 && !(1e-5..1e16).contains(&magnitude) {
+        format!("{:e}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+/// Renders a FLOAT column value, which the binary protocol returns as `f32`.
+pub fn format_f32(value: f32) -> String {
+    format_float(value, value.is_finite(), value.abs() as f64)
+}
+
+/// Renders a DOUBLE column value.
+pub fn format_f64(value: f64) -> String {
+    format_float(value, value.is_finite(), value.abs())
+}
+
+/// Whether a value can be written as an unquoted numeric literal.
+pub fn is_numeric_literal(value: &str) -> bool {
+    !value.is_empty()
+        && value.bytes().all(|b| b.is_ascii_digit() || matches!(b, b'+' | b'-' | b'.' | b'e' | b'E'))
+        && value.parse::<f64>().is_ok()
+}
@@ -0,0 +1,551 @@
+use std::cmp::Ordering;
+use regex::Regex;
+use serde_json::Value;
+use crate::mysql_utils::json_to_string;
+use crate::tables::{Condition, Operator, Override, OverrideKind};
+
+/// A condition resolved against the exported columns.
+#[derive(Debug, Clone)]
+struct ColumnMatcher {
+    position: usize,
+    operator: Operator,
+    values: Vec<Value>,
+    /// Compiled pattern for `like` and `regex`
+    pattern: Option<Regex>,
+}
+
+/// Formatting applied to a placeholder's value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    Upper,
+    Lower,
+    /// Pads on the left to a width, with `0` unless another character is given
+    Pad(usize, char),
+    Truncate(usize),
+    /// Replaces NULL, which otherwise renders as an empty string
+    Default(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplatePart {
+    Text(String),
+    Field {
+        column: String,
+        original: bool,
+        filters: Vec<Filter>,
+    },
+}
+
+/// A `set` value, with its placeholders resolved to column positions.
+#[derive(Debug, Clone)]
+enum SetValue {
+    Literal(String),
+    Template(Vec<(TemplatePart, Option<usize>)>),
+}
+
+/// A `value` override resolved against the exported columns.
+#[derive(Debug, Clone)]
+pub struct ValueOverride {
+    all: Vec<ColumnMatcher>,
+    any: Vec<ColumnMatcher>,
+    set: Vec<(usize, SetValue)>,
+}
+
+/// Conditions a row must all meet, resolved against the exported columns.
+#[derive(Debug, Clone)]
+pub struct RowFilter {
+    all: Vec<ColumnMatcher>,
+}
+
+impl RowFilter {
+    pub fn matches(&self, values: &[String]) -> bool {
+        self.all.iter().all(|matcher| matches(matcher, &values[matcher.position]))
+    }
+}
+
+fn parse_filter(filter: &str) -> Result<Filter, String> {
+    let mut parts = filter.split(':');
+    let name = parts.next().unwrap_or_default().trim();
+    let argument = parts.next();
+    let number = |argument: Option<&str>| -> Result<usize, String> {
+        argument
+            .and_then(|argument| argument.trim().parse().ok())
+            .ok_or_else(|| format!("filter {} needs a number", name))
+    };
+    match name {
+        "upper" => Ok(Filter::Upper),
+        "lower" => Ok(Filter::Lower),
+        "pad" => {
+            let width = number(argument)?;
+            let fill = parts.next().and_then(|fill| fill.chars().next()).unwrap_or('0');
+            Ok(Filter::Pad(width, fill))
+        }
+        "truncate" => Ok(Filter::Truncate(number(argument)?)),
+        // Everything after the first `:`, which may itself contain `:`.
+        "default" => Ok(Filter::Default(filter.split_once(':').map_or("", |(_, text)| text).to_string())),
+        other => Err(format!("unknown filter {}", other)),
+    }
+}
+
+/// Parses a placeholder body such as `first_name|upper|truncate:10`, or
+/// returns `None` if it is not a placeholder.
+fn parse_placeholder(body: &str) -> Option<Result<TemplatePart, String>> {
+    let (field, filters) = match body.split_once('|') {
+        Some((field, filters)) => (field.trim(), Some(filters)),
+        None => (body.trim(), None),
+    };
+    let (column, original) = match field.strip_prefix("original.") {
+        Some(column) => (column, true),
+        None => (field, false),
+    };
+    let identifier = column.chars().next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && column.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '$');
+    if !identifier {
+        return None;
+    }
+    let filters: Result<Vec<Filter>, String> = filters
+        .map(|filters| filters.split('|').map(parse_filter).collect())
+        .unwrap_or_else(|| Ok(Vec::new()));
+    Some(filters.map(|filters| TemplatePart::Field {
+        column: column.to_string(),
+        original,
+        filters,
+    }))
+}
+
+/// Splits a `set` string into text and `{column|filter}` placeholders.
+/// `{{` and `}}` stand for literal braces, and braces around anything that
+/// is not a column name, such as JSON, are kept as they are.
+pub fn parse_template(template: &str) -> Result<Vec<TemplatePart>, String> {
+    let mut parts: Vec<TemplatePart> = Vec::new();
+    let mut text = String::new();
+    let mut rest = template;
+    while let Some(c) = rest.chars().next() {
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            text.push(c);
+            rest = &rest[2..];
+            continue;
+        }
+        if c == '{' {
+            if let Some(end) = rest.find('}') {
+                if let Some(part) = parse_placeholder(&rest[1..end]) {
+                    if !text.is_empty() {
+                        parts.push(TemplatePart::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(part.map_err(|err| format!("{} in {}", err, template))?);
+                    rest = &rest[end + 1..];
+                    continue;
+                }
+            }
+        }
+        text.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    if !text.is_empty() {
+        parts.push(TemplatePart::Text(text));
+    }
+    Ok(parts)
+}
+
+fn resolve_set_value(table: &str, columns: &[String], value: &Value) -> SetValue {
+    let template = match value {
+        Value::String(template) => template,
+        other => return SetValue::Literal(json_to_string(other)),
+    };
+    let parts = parse_template(template).unwrap_or_else(|err| panic!("{}: {}", table, err));
+    if parts.iter().all(|part| matches!(part, TemplatePart::Text(_))) {
+        return SetValue::Literal(parts.into_iter().map(|part| match part {
+            TemplatePart::Text(text) => text,
+            TemplatePart::Field { .. } => unreachable!(),
+        }).collect());
+    }
+    SetValue::Template(parts.into_iter().map(|part| {
+        let position = match &part {
+            TemplatePart::Field { column, .. } => Some(
+                columns
+                    .iter()
+                    .position(|col| col == column)
+                    .unwrap_or_else(|| panic!("{}: template column {} is not exported", table, column)),
+            ),
+            TemplatePart::Text(_) => None,
+        };
+        (part, position)
+    }).collect())
+}
+
+fn render(parts: &[(TemplatePart, Option<usize>)], current: &[String], original: &[String]) -> String {
+    let mut rendered = String::new();
+    for (part, position) in parts {
+        let (filters, value) = match (part, position) {
+            (TemplatePart::Text(text), _) => {
+                rendered.push_str(text);
+                continue;
+            }
+            (TemplatePart::Field { original: true, filters, .. }, Some(position)) => (filters, &original[*position]),
+            (TemplatePart::Field { filters, .. }, Some(position)) => (filters, &current[*position]),
+            (TemplatePart::Field { .. }, None) => unreachable!(),
+        };
+        let mut value: Option<String> = if value == "NULL" { None } else { Some(value.clone()) };
+        for filter in filters {
+            value = match (filter, value) {
+                (Filter::Default(default), None) => Some(default.clone()),
+                (_, None) => None,
+                (Filter::Upper, Some(value)) => Some(value.to_uppercase()),
+                (Filter::Lower, Some(value)) => Some(value.to_lowercase()),
+                (Filter::Pad(width, fill), Some(value)) => {
+                    let count = value.chars().count();
+                    Some(std::iter::repeat_n(*fill, width.saturating_sub(count)).chain(value.chars()).collect())
+                }
+                (Filter::Truncate(length), Some(value)) => Some(value.chars().take(*length).collect()),
+                (Filter::Default(_), Some(value)) => Some(value),
+            };
+        }
+        rendered.push_str(&value.unwrap_or_default());
+    }
+    rendered
+}
+
+/// Turns a `like` pattern into a case-insensitive regular expression,
+/// following MySQL's default collations.
+fn like_to_regex(pattern: &str) -> String {
+    let mut regex = String::from("(?is)^");
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => regex.push_str(".*"),
+            '_' => regex.push('.'),
+            '\\' => match chars.next() {
+                Some(escaped) => regex.push_str(&regex::escape(&escaped.to_string())),
+                None => regex.push_str(&regex::escape("\\")),
+            },
+            other => regex.push_str(&regex::escape(&other.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// Checks that a condition has the values its operator needs, and compiles
+/// its pattern.
+pub fn compile_pattern(condition: &Condition) -> Result<Option<Regex>, String> {
+    let values = condition.values();
+    let expected = match condition.op {
+        Operator::In | Operator::NotIn => None,
+        Operator::Between => Some(2),
+        _ => Some(1),
+    };
+    match expected {
+        Some(count) if values.len() != count => {
+            return Err(format!(
+                "{:?} on {} needs {} value(s), use {}",
+                condition.op,
+                condition.name,
+                count,
+                if count == 1 { "\"value\"" } else { "\"values\": [low, high]" }
+            ));
+        }
+        None if condition.values.is_none() => {
+            return Err(format!("{:?} on {} needs a \"values\" list", condition.op, condition.name));
+        }
+        _ => {}
+    }
+    let source = match condition.op {
+        Operator::Like => like_to_regex(&json_to_string(&values[0])),
+        Operator::Regex => json_to_string(&values[0]),
+        _ => return Ok(None),
+    };
+    Regex::new(&source)
+        .map(Some)
+        .map_err(|err| format!("invalid pattern on {}: {}", condition.name, err))
+}
+
+fn resolve(
+    table: &str,
+    columns: &[String],
+    condition: &Condition,
+) -> Option<ColumnMatcher> {
+    let position = match columns.iter().position(|col| col == &condition.name) {
+        Some(position) => position,
+        None => {
+            eprintln!("{}: override condition column {} is not exported", table, condition.name);
+            return None;
+        }
+    };
+    let pattern = compile_pattern(condition)
+        .unwrap_or_else(|err| panic!("{}: {}", table, err));
+    Some(ColumnMatcher {
+        position,
+        operator: condition.op,
+        values: condition.values(),
+        pattern,
+    })
+}
+
+/// Resolves the `value` overrides of a table once, before rows are read.
+/// An override whose conditions name a column that is not exported never
+/// matches, so it is left out.
+pub fn get_overrides(
+    table: &str,
+    columns: &[String],
+    overrides: &Option<Vec<Override>>,
+) -> Vec<ValueOverride> {
+    let mut resolved: Vec<ValueOverride> = Vec::new();
+    for o in overrides.iter().flatten() {
+        if o.kind != OverrideKind::Value {
+            continue;
+        }
+        let all: Option<Vec<ColumnMatcher>> = o
+            .conditions()
+            .iter()
+            .map(|condition| resolve(table, columns, condition))
+            .collect();
+        let any: Vec<ColumnMatcher> = o
+            .any
+            .iter()
+            .flatten()
+            .filter_map(|condition| resolve(table, columns, condition))
+            .collect();
+        let all = match all {
+            Some(all) if o.any.is_none() || !any.is_empty() => all,
+            _ => continue,
+        };
+        let set = o.set.iter().filter_map(|(column, value)| {
+            columns
+                .iter()
+                .position(|col| col == column)
+                .map(|position| (position, resolve_set_value(table, columns, value)))
+        }).collect();
+        resolved.push(ValueOverride { all, any, set });
+    }
+    resolved
+}
+
+/// Resolves the conditions of a row filter, which must name exported
+/// columns.
+pub fn get_filter(table: &str, columns: &[String], conditions: &[Condition]) -> RowFilter {
+    let all = conditions.iter().map(|condition| {
+        if !columns.contains(&condition.name) {
+            panic!("{}: filter column {} is not exported", table, condition.name);
+        }
+        resolve(table, columns, condition).unwrap()
+    }).collect();
+    RowFilter { all }
+}
+
+/// Applies the overrides in order, each one to the row as the previous
+/// ones left it. Templates read the row before the override's own `set`,
+/// or the row before any override with `original.`.
+pub fn apply(values: &mut [String], overrides: &[ValueOverride]) {
+    if overrides.is_empty() {
+        return;
+    }
+    let original: Vec<String> = values.to_vec();
+    for o in overrides {
+        let matched = o.all.iter().all(|matcher| matches(matcher, &values[matcher.position]))
+            && (o.any.is_empty() || o.any.iter().any(|matcher| matches(matcher, &values[matcher.position])));
+        if !matched {
+            continue;
+        }
+        let rendered: Vec<(usize, String)> = o.set.iter().map(|(position, value)| {
+            let value = match value {
+                SetValue::Literal(value) => value.clone(),
+                SetValue::Template(parts) => render(parts, values, &original),
+            };
+            (*position, value)
+        }).collect();
+        for (position, value) in rendered {
+            values[position] = value;
+        }
+    }
+}
+
+/// Compares a column value with a configured value: numerically when the
+/// configured value is a JSON number or boolean, as text otherwise.
+fn compare(value: &str, expected: &Value) -> Option<Ordering> {
+    if expected.is_null() || value == "NULL" {
+        return None;
+    }
+    let expected_text = json_to_string(expected);
+    if expected.is_number() || expected.is_boolean() {
+        if let (Ok(left), Ok(right)) = (value.trim().parse::<f64>(), expected_text.parse::<f64>()) {
+            return left.partial_cmp(&right);
+        }
+    }
+    Some(value.cmp(expected_text.as_str()))
+}
+
+fn equals(value: &str, expected: &Value) -> bool {
+    match expected {
+        Value::Null => value == "NULL",
+        _ => compare(value, expected) == Some(Ordering::Equal),
+    }
+}
+
+fn matches(matcher: &ColumnMatcher, value: &str) -> bool {
+    let expected = &matcher.values;
+    match matcher.operator {
+        Operator::Eq => equals(value, &expected[0]),
+        // As in SQL, NULL is only unequal to `null` itself.
+        Operator::Ne if expected[0].is_null() => value != "NULL",
+        Operator::Ne => value != "NULL" && !equals(value, &expected[0]),
+        Operator::In => expected.iter().any(|expected| equals(value, expected)),
+        Operator::NotIn => value != "NULL" && !expected.iter().any(|expected| equals(value, expected)),
+        Operator::Lt => compare(value, &expected[0]) == Some(Ordering::Less),
+        Operator::Le => matches!(compare(value, &expected[0]), Some(Ordering::Less | Ordering::Equal)),
+        Operator::Gt => compare(value, &expected[0]) == Some(Ordering::Greater),
+        Operator::Ge => matches!(compare(value, &expected[0]), Some(Ordering::Greater | Ordering::Equal)),
+        Operator::Between => {
+            matches!(compare(value, &expected[0]), Some(Ordering::Greater | Ordering::Equal))
+                && matches!(compare(value, &expected[1]), Some(Ordering::Less | Ordering::Equal))
+        }
+        Operator::Like | Operator::Regex => {
+            value != "NULL" && matcher.pattern.as_ref().is_some_and(|pattern| pattern.is_match(value))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn columns() -> Vec<String> {
+        vec!["id".to_string(), "name".to_string()]
+    }
+
+    fn render_template(template: &str, row: &[&str]) -> String {
+        let row: Vec<String> = row.iter().map(|value| value.to_string()).collect();
+        match resolve_set_value("t", &columns(), &Value::from(template)) {
+            SetValue::Template(parts) => render(&parts, &row, &row),
+            SetValue::Literal(text) => text,
+        }
+    }
+
+    fn matcher(condition: Value) -> ColumnMatcher {
+        let condition: Condition = serde_json::from_value(condition).unwrap();
+        resolve("t", &columns(), &condition).unwrap()
+    }
+
+    fn test(condition: Value, value: &str) -> bool {
+        matches(&matcher(condition), value)
+    }
+
+    #[test]
+    fn compares_numbers_numerically() {
+        assert!(test(json!({"name": "id", "value": 23}), "23.00"));
+        assert!(test(json!({"name": "id", "op": ">", "value": 9}), "10"));
+        assert!(test(json!({"name": "id", "op": "=", "value": true}), "1"));
+        assert!(test(json!({"name": "id", "op": "between", "values": [1, 10]}), "10"));
+        assert!(!test(json!({"name": "id", "op": "between", "values": [1, 10]}), "10.5"));
+        assert!(test(json!({"name": "id", "op": "in", "values": [1, 2.5]}), "2.50"));
+    }
+
+    #[test]
+    fn compares_strings_as_text() {
+        assert!(!test(json!({"name": "id", "value": "23"}), "23.00"));
+        assert!(!test(json!({"name": "id", "op": ">", "value": "9"}), "10"));
+        assert!(test(json!({"name": "name", "op": "<", "value": "b"}), "abc"));
+        // A number only compares numerically with a numeric value.
+        assert!(test(json!({"name": "name", "op": ">", "value": 9}), "x"));
+    }
+
+    #[test]
+    fn escapes_like_patterns() {
+        let like = |pattern: &str, value: &str| test(json!({"name": "name", "op": "like", "value": pattern}), value);
+        assert!(like("a%", "ABC"));
+        assert!(like("a_c", "abc"));
+        assert!(!like("a_c", "abbc"));
+        assert!(like("100\\%", "100%"));
+        assert!(!like("100\\%", "1000"));
+        assert!(like("a\\_b", "a_b"));
+        assert!(!like("a\\_b", "axb"));
+        assert!(like("(a.b)*", "(a.b)*"));
+        assert!(!like("a.b", "axb"));
+        assert!(like("[x]+?", "[X]+?"));
+        assert!(like("line%end", "line\nend"));
+    }
+
+    #[test]
+    fn treats_null_as_in_sql() {
+        assert!(test(json!({"name": "name", "value": null}), "NULL"));
+        assert!(!test(json!({"name": "name", "value": null}), "x"));
+        assert!(!test(json!({"name": "name", "value": "x"}), "NULL"));
+        assert!(!test(json!({"name": "name", "op": "!=", "value": "x"}), "NULL"));
+        assert!(test(json!({"name": "name", "op": "!=", "value": null}), "x"));
+        assert!(!test(json!({"name": "name", "op": "!=", "value": null}), "NULL"));
+        assert!(!test(json!({"name": "name", "op": "not_in", "values": ["x"]}), "NULL"));
+        assert!(!test(json!({"name": "id", "op": "<", "value": 5}), "NULL"));
+        assert!(!test(json!({"name": "name", "op": "like", "value": "%"}), "NULL"));
+        assert!(!test(json!({"name": "name", "op": "regex", "value": ".*"}), "NULL"));
+    }
+
+    #[test]
+    fn combines_all_and_any() {
+        let overrides: Vec<Override> = serde_json::from_value(json!([{
+            "name": "id",
+            "op": ">",
+            "value": 1,
+            "any": [{"name": "name", "value": "a"}, {"name": "name", "value": "b"}],
+            "set": {"name": "{name|upper}"}
+        }])).unwrap();
+        let overrides = get_overrides("t", &columns(), &Some(overrides));
+        let apply_to = |row: [&str; 2]| {
+            let mut row: Vec<String> = row.iter().map(|value| value.to_string()).collect();
+            apply(&mut row, &overrides);
+            row[1].clone()
+        };
+        assert_eq!(apply_to(["2", "b"]), "B");
+        assert_eq!(apply_to(["2", "c"]), "c");
+        assert_eq!(apply_to(["1", "a"]), "a");
+    }
+
+    #[test]
+    fn rejects_missing_values() {
+        let condition = |condition: Value| compile_pattern(&serde_json::from_value(condition).unwrap());
+        assert!(condition(json!({"name": "id", "op": "between", "value": 1})).is_err());
+        assert!(condition(json!({"name": "id", "op": "in", "value": 1})).is_err());
+        assert!(condition(json!({"name": "id", "op": "regex", "value": "("})).is_err());
+    }
+
+    #[test]
+    fn parses_placeholders_and_filters() {
+        assert_eq!(
+            parse_template("id {{{ id | pad:4 }}}").unwrap(),
+            vec![
+                TemplatePart::Text("id {".to_string()),
+                TemplatePart::Field { column: "id".to_string(), original: false, filters: vec![Filter::Pad(4, '0')] },
+                TemplatePart::Text("}".to_string()),
+            ]
+        );
+        assert_eq!(
+            parse_template("{original.name|truncate:3|upper}").unwrap(),
+            vec![TemplatePart::Field {
+                column: "name".to_string(),
+                original: true,
+                filters: vec![Filter::Truncate(3), Filter::Upper],
+            }]
+        );
+        assert_eq!(parse_template("{\"a\": 1}").unwrap(), vec![TemplatePart::Text("{\"a\": 1}".to_string())]);
+        assert!(parse_template("{name|shout}").is_err());
+        assert!(parse_template("{name|pad}").is_err());
+    }
+
+    #[test]
+    fn reads_default_text_after_the_first_colon() {
+        assert_eq!(parse_filter(" default:N/A").unwrap(), Filter::Default("N/A".to_string()));
+        assert_eq!(parse_filter("default:12:30").unwrap(), Filter::Default("12:30".to_string()));
+        assert_eq!(parse_filter("default").unwrap(), Filter::Default(String::new()));
+    }
+
+    #[test]
+    fn renders_templates() {
+        assert_eq!(render_template("{name | default:N/A}", &["1", "NULL"]), "N/A");
+        assert_eq!(render_template("{name|default:at 12:30}", &["1", "NULL"]), "at 12:30");
+        assert_eq!(render_template("{name|default:N/A}", &["1", "Ann"]), "Ann");
+        assert_eq!(render_template("{name}!", &["1", "NULL"]), "!");
+        assert_eq!(render_template("#{ id | pad:5 }", &["42", "Ann"]), "#00042");
+        assert_eq!(render_template("{id|pad:4:*}", &["42", "Ann"]), "**42");
+        assert_eq!(render_template("{id|pad:1}", &["42", "Ann"]), "42");
+        assert_eq!(render_template("{name|upper|truncate:2}", &["1", "émile"]), "ÉM");
+    }
+}
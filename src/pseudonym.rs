@@ -0,0 +1,352 @@
+use std::fs;
+use hmac::{Hmac, Mac};
+use rpassword::read_password;
+use serde::Deserialize;
+use sha2::Sha256;
+use arguments::Args;
+use crate::arguments;
+use crate::spatial::to_hex;
+use crate::schema::ColumnInfo;
+use crate::tables::{MaskRule, PseudonymMask, TableConfig};
+
+const FEISTEL_ROUNDS: u8 = 8;
+
+/// Output of a `pseudonymize` mask.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PseudonymFormat {
+    /// 32 hex characters
+    #[default]
+    Hex,
+    /// An address with a pseudonymous local part
+    Email,
+    /// A number of the mask's domain
+    Numeric,
+}
+
+/// Numbers a `numeric` pseudonym can take, from `min` to `max` in units
+/// of the `scale`th decimal place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NumericDomain {
+    pub min: i128,
+    pub max: i128,
+    pub scale: u32,
+}
+
+/// Type of numeric pseudonyms whose mask gives no `domain`.
+const DEFAULT_DOMAIN: &str = "bigint";
+/// Whole numbers a float can hold exactly.
+const FLOAT_MAX: i128 = (1 << 53) - 1;
+/// Widest decimal whose numbers fit an i128.
+const DECIMAL_DIGITS: u32 = 38;
+
+/// Resolves the range of a numeric type such as `int(10) unsigned` or
+/// `decimal(12,2)`, or None for other types. Decimals wider than 38
+/// digits are limited to 38.
+pub fn numeric_domain(column_type: &str) -> Option<NumericDomain> {
+    let column_type = column_type.trim().to_lowercase();
+    let unsigned = column_type.contains("unsigned");
+    let base = column_type.split(['(', ' ']).next().unwrap_or_default();
+    let bits = match base {
+        "tinyint" => 8,
+        "smallint" => 16,
+        "mediumint" => 24,
+        "int" | "integer" => 32,
+        "bigint" => 64,
+        "decimal" | "numeric" => {
+            let arguments = column_type
+                .split_once('(')
+                .and_then(|(_, rest)| rest.split_once(')'))
+                .map_or("10,0", |(arguments, _)| arguments);
+            let (precision, scale) = arguments.split_once(',').unwrap_or((arguments, "0"));
+            let precision: u32 = precision.trim().parse().unwrap_or(10);
+            let scale: u32 = scale.trim().parse().unwrap_or(0);
+            let max = 10i128.pow(precision.min(DECIMAL_DIGITS)) - 1;
+            return Some(NumericDomain { min: if unsigned { 0 } else { -max }, max, scale });
+        }
+        "float" | "double" | "real" => {
+            return Some(NumericDomain { min: if unsigned { 0 } else { -FLOAT_MAX }, max: FLOAT_MAX, scale: 0 });
+        }
+        _ => return None,
+    };
+    Some(if unsigned {
+        NumericDomain { min: 0, max: (1i128 << bits) - 1, scale: 0 }
+    } else {
+        NumericDomain { min: -(1i128 << (bits - 1)), max: (1i128 << (bits - 1)) - 1, scale: 0 }
+    })
+}
+
+impl NumericDomain {
+    /// Whether every number of `other` is also a number of this domain.
+    pub fn contains(&self, other: &NumericDomain) -> bool {
+        if other.scale > self.scale {
+            return false;
+        }
+        let unit = 10i128.checked_pow(self.scale - other.scale);
+        let scaled = |number: i128| unit.and_then(|unit| number.checked_mul(unit));
+        match (scaled(other.min), scaled(other.max)) {
+            (Some(min), Some(max)) => self.min <= min && max <= self.max,
+            _ => false,
+        }
+    }
+}
+
+/// Resolves the domain of a `numeric` pseudonym. It comes from the mask
+/// and not from the column, so an ID pseudonymizes to the same number in
+/// every column it appears in, whatever their types. Numeric columns must
+/// hold every number of the domain.
+pub fn get_domain(table: &str, column: &str, rule: &PseudonymMask, info: Option<&ColumnInfo>) -> Option<NumericDomain> {
+    if rule.format != PseudonymFormat::Numeric {
+        return None;
+    }
+    let name = rule.domain.as_deref().unwrap_or(DEFAULT_DOMAIN);
+    let domain = numeric_domain(name)
+        .unwrap_or_else(|| panic!("{}: pseudonymize domain {} of {} is not a numeric type", table, name, column));
+    if let Some(column_domain) = info.and_then(|info| numeric_domain(&info.column_type)) {
+        if !column_domain.contains(&domain) {
+            panic!(
+                "{}: {} cannot hold numeric pseudonyms of domain {}, set \"domain\" to a type it can hold, the same in every table the column is joined with",
+                table, column, name
+            );
+        }
+    }
+    Some(domain)
+}
+
+/// Keyed pseudonyms: the same input and secret always give the same
+/// output, in every table and every run.
+pub struct Pseudonymizer {
+    secret: Vec<u8>,
+}
+
+impl Pseudonymizer {
+    fn mac(&self, parts: &[&[u8]]) -> [u8; 32] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        for part in parts {
+            mac.update(part);
+        }
+        mac.finalize().into_bytes().into()
+    }
+
+    /// `domain` is the one resolved by `get_domain`, which `numeric`
+    /// needs.
+    pub fn pseudonymize(&self, value: &str, format: PseudonymFormat, keep_domain: bool, domain: Option<&NumericDomain>) -> String {
+        match format {
+            PseudonymFormat::Hex => to_hex(&self.mac(&[b"hex", value.as_bytes()])[..16]).to_lowercase(),
+            PseudonymFormat::Email => self.email(value, keep_domain),
+            PseudonymFormat::Numeric => self.numeric(value, domain.expect("numeric pseudonyms have a domain")),
+        }
+    }
+
+    /// Emails compare case-insensitively, so they are lowercased first.
+    fn email(&self, value: &str, keep_domain: bool) -> String {
+        let value = value.trim().to_lowercase();
+        let (local, domain) = value.rsplit_once('@').unwrap_or((value.as_str(), ""));
+        let hash = self.mac(&[b"email", local.as_bytes(), b"@", domain.as_bytes()]);
+        let domain = if keep_domain && !domain.is_empty() { domain } else { "example.com" };
+        format!("u{}@{}", to_hex(&hash[..8]).to_lowercase(), domain)
+    }
+
+    /// Maps a number of the domain to another of the same sign in the
+    /// domain. The mapping is a permutation, so distinct IDs stay distinct.
+    /// A value that is not such a number, such as text, gets a number of
+    /// the domain from its hash.
+    fn numeric(&self, value: &str, domain: &NumericDomain) -> String {
+        let NumericDomain { min, max, scale } = *domain;
+        // Domains hold at most 38 digits on either side of zero, so their
+        // sizes fit a u128.
+        let number = match parse_scaled(value, scale) {
+            Some(number) if (min..=max).contains(&number) => number,
+            _ => {
+                let hash = self.mac(&[b"numeric", value.as_bytes()]);
+                let offset = u128::from_le_bytes(hash[..16].try_into().unwrap()) % (max.abs_diff(min) + 1);
+                return format_scaled(min.checked_add_unsigned(offset).expect("the offset is within the domain"), scale);
+            }
+        };
+        let (low, high) = if number < 0 { (min, max.min(-1)) } else { (min.max(0), max) };
+        let permuted = self.permute(number.abs_diff(low), high.abs_diff(low) + 1);
+        format_scaled(low.checked_add_unsigned(permuted).expect("the permutation is within the domain"), scale)
+    }
+
+    /// Permutes `0..size` with a keyed Feistel network over the smallest
+    /// even number of bits that covers `size`, walking the cycle until the
+    /// result falls back into range.
+    fn permute(&self, mut value: u128, size: u128) -> u128 {
+        let mut bits = 128 - (size - 1).max(1).leading_zeros();
+        bits += bits % 2;
+        let half = bits / 2;
+        let mask = (1u128 << half) - 1;
+        loop {
+            let mut left = value >> half;
+            let mut right = value & mask;
+            for round in 0..FEISTEL_ROUNDS {
+                let hash = self.mac(&[b"numeric", &size.to_le_bytes(), &[round], &right.to_le_bytes()]);
+                let f = u128::from_le_bytes(hash[..16].try_into().unwrap()) & mask;
+                (left, right) = (right, left ^ f);
+            }
+            value = (left << half) | right;
+            if value < size {
+                return value;
+            }
+        }
+    }
+}
+
+/// Reads `123.45` as 12345 at scale 2. Fails for more decimals than the
+/// scale, or a number too large for the range.
+fn parse_scaled(value: &str, scale: u32) -> Option<i128> {
+    let (sign, digits) = match value.strip_prefix('-') {
+        Some(digits) => (-1, digits),
+        None => (1, value),
+    };
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    if whole.is_empty()
+        || fraction.len() > scale as usize
+        || !whole.bytes().chain(fraction.bytes()).all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+    let padded = format!("{}{}{}", whole, fraction, "0".repeat(scale as usize - fraction.len()));
+    padded.parse::<i128>().ok().map(|number| sign * number)
+}
+
+/// Writes a number in units of the `scale`th decimal place as a decimal.
+fn format_scaled(number: i128, scale: u32) -> String {
+    if scale == 0 {
+        return number.to_string();
+    }
+    let unit = 10i128.pow(scale);
+    let sign = if number < 0 { "-" } else { "" };
+    let number = number.unsigned_abs();
+    format!("{}{}.{:0width$}", sign, number / unit as u128, number % unit as u128, width = scale as usize)
+}
+
+/// Reads the pseudonymization secret if any table uses `pseudonymize`,
+/// prompting for it when neither option gives one.
+pub fn get_pseudonymizer(args: &Args, table_config: &[TableConfig]) -> Option<Pseudonymizer> {
+    let used = table_config.iter().any(|table| {
+        table.mask.iter().flatten().any(|(_, rule)| matches!(rule, MaskRule::Pseudonymize(_)))
+    });
+    if !used {
+        return None;
+    }
+    let secret = read_secret(&args.pseudonym_secret_file, &args.pseudonym_secret, "pseudonymization");
+    Some(Pseudonymizer { secret })
+}
+
+/// Reads a secret from its file, its option, or a prompt, in that order.
+pub fn read_secret(secret_file: &Option<String>, secret: &Option<String>, name: &str) -> Vec<u8> {
+    let secret: Vec<u8> = if let Some(secret_file) = secret_file {
+        fs::read(secret_file).unwrap_or_else(|err| panic!("Failed to read {} secret file: {}", name, err))
+    } else if let Some(secret) = secret {
+        secret.as_bytes().to_vec()
+    } else {
+        println!("Please enter your {} secret: ", name);
+        read_password().unwrap().trim().as_bytes().to_vec()
+    };
+    if secret.is_empty() {
+        panic!("The {} secret must not be empty", name);
+    }
+    secret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pseudonymizer() -> Pseudonymizer {
+        Pseudonymizer { secret: b"secret".to_vec() }
+    }
+
+    fn numeric(value: &str, domain: &str) -> String {
+        pseudonymizer().pseudonymize(value, PseudonymFormat::Numeric, false, numeric_domain(domain).as_ref())
+    }
+
+    fn domain(min: i128, max: i128, scale: u32) -> NumericDomain {
+        NumericDomain { min, max, scale }
+    }
+
+    fn info(column_type: &str) -> ColumnInfo {
+        ColumnInfo { column_type: column_type.to_string(), ..Default::default() }
+    }
+
+    fn rule(domain: Option<&str>) -> PseudonymMask {
+        PseudonymMask { format: PseudonymFormat::Numeric, keep_domain: false, domain: domain.map(str::to_string) }
+    }
+
+    #[test]
+    fn resolves_column_types() {
+        assert_eq!(numeric_domain("tinyint(3) unsigned"), Some(domain(0, 255, 0)));
+        assert_eq!(numeric_domain("INT"), Some(domain(-2147483648, 2147483647, 0)));
+        assert_eq!(numeric_domain("decimal(5,2)"), Some(domain(-99999, 99999, 2)));
+        assert_eq!(numeric_domain("varchar(20)"), None);
+    }
+
+    #[test]
+    fn checks_that_columns_hold_the_domain() {
+        let int = numeric_domain("int").unwrap();
+        assert!(numeric_domain("bigint").unwrap().contains(&int));
+        assert!(numeric_domain("decimal(12,2)").unwrap().contains(&int));
+        assert!(!numeric_domain("int unsigned").unwrap().contains(&int));
+        assert!(!numeric_domain("smallint").unwrap().contains(&int));
+        assert!(!int.contains(&numeric_domain("decimal(5,2)").unwrap()));
+        assert!(!numeric_domain("decimal(38,30)").unwrap().contains(&numeric_domain("bigint").unwrap()));
+    }
+
+    #[test]
+    fn shares_the_domain_between_columns() {
+        let shared = get_domain("users", "id", &rule(Some("int unsigned")), Some(&info("int unsigned")));
+        for column_type in ["bigint", "bigint unsigned", "varchar(20)"] {
+            assert_eq!(get_domain("orders", "user_id", &rule(Some("int unsigned")), Some(&info(column_type))), shared);
+        }
+        assert_eq!(get_domain("users", "id", &rule(None), Some(&info("bigint"))), numeric_domain("bigint"));
+        let hex = PseudonymMask { format: PseudonymFormat::Hex, ..rule(None) };
+        assert_eq!(get_domain("users", "email", &hex, Some(&info("varchar(100)"))), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot hold numeric pseudonyms")]
+    fn rejects_columns_too_small_for_the_domain() {
+        get_domain("users", "id", &rule(None), Some(&info("int")));
+    }
+
+    #[test]
+    fn permutes_within_the_domain() {
+        let mut seen: Vec<u8> = (0..=255).map(|value: u16| numeric(&value.to_string(), "tinyint unsigned").parse().unwrap()).collect();
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), 256);
+        for value in ["-128", "-1"] {
+            let masked: i8 = numeric(value, "tinyint").parse().unwrap();
+            assert!(masked < 0);
+        }
+        let masked: u64 = numeric("18446744073709551615", "bigint unsigned").parse().unwrap();
+        assert!(masked > 0);
+    }
+
+    #[test]
+    fn keeps_the_scale_of_decimals() {
+        let masked = numeric("123.4", "decimal(5,2)");
+        let (whole, fraction) = masked.split_once('.').unwrap();
+        assert_eq!(fraction.len(), 2);
+        assert!(whole.parse::<i32>().unwrap().abs() <= 999);
+        assert_eq!(numeric("123.4", "decimal(5,2)"), numeric("123.40", "decimal(5,2)"));
+    }
+
+    #[test]
+    fn handles_the_widest_decimals() {
+        let widest = "9".repeat(38);
+        assert_eq!(numeric_domain("decimal(65,0)"), numeric_domain("decimal(38,0)"));
+        for value in [widest.as_str(), "-1", "0", "not a number"] {
+            let masked: f64 = numeric(value, "decimal(38,0)").parse().unwrap();
+            assert!(masked.abs() < 1e38);
+        }
+        assert!(numeric("-12.5", "decimal(65,30)").starts_with('-'));
+    }
+
+    #[test]
+    fn hashes_text_into_the_domain() {
+        let masked: u8 = numeric("AB-12", "tinyint unsigned").parse().unwrap();
+        assert_eq!(numeric("AB-12", "tinyint unsigned"), masked.to_string());
+    }
+}
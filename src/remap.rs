@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use clap::ValueEnum;
+use mysql::prelude::Queryable;
+use mysql::Value as MySqlValue;
+use crate::database::Database;
+use crate::encryption::{self, Encryption};
+use crate::schema;
+use crate::state::StateStore;
+use crate::subset;
+use crate::tables::{RemapStrategy, TableConfig};
+
+/// What is written for a reference to a row that has no new ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Unmapped {
+    /// Keep the old ID and count it in a report
+    Keep,
+    /// Write NULL and count it in a report
+    Null,
+    /// Stop the export of the table
+    Fail,
+}
+
+/// IDs are held as i128, which covers both BIGINT and BIGINT UNSIGNED.
+pub type Id = i128;
+
+/// New IDs of one remapped table.
+#[derive(Debug)]
+pub struct TableRemap {
+    pub column: String,
+    /// Added to every ID by the offset strategy
+    offset: Option<Id>,
+    /// Old to new IDs of the exported rows
+    mapping: HashMap<Id, Id>,
+}
+
+impl TableRemap {
+    /// The new ID, if the row has one that an integer column can hold.
+    fn map(&self, old: Id) -> Option<Id> {
+        let new = match self.offset {
+            Some(offset) => old.checked_add(offset),
+            None => self.mapping.get(&old).copied(),
+        };
+        new.filter(|new| (i64::MIN as Id..=u64::MAX as Id).contains(new))
+    }
+}
+
+/// Every remapped table, and the columns that hold their IDs.
+#[derive(Debug, Default)]
+pub struct RemapPlan {
+    tables: HashMap<String, TableRemap>,
+    /// Table to its columns referencing a remapped table
+    references: HashMap<String, Vec<(String, String)>>,
+}
+
+/// A column of an exported table whose values are rewritten.
+#[derive(Debug)]
+pub struct ColumnRemap<'a> {
+    position: usize,
+    column: &'a str,
+    target: &'a TableRemap,
+    /// What to write for an ID without a new value
+    unmapped: Unmapped,
+}
+
+/// IDs that had no new value, per column, and whether they became NULL.
+#[derive(Debug, Default)]
+pub struct RemapReport {
+    pub unmapped: HashMap<String, (usize, bool)>,
+}
+
+impl RemapReport {
+    pub fn print(&self, table: &str) {
+        for (column, (count, nulled)) in &self.unmapped {
+            eprintln!(
+                "{}.{}: {} values reference rows that were not remapped and were {}",
+                table, column, count, if *nulled { "written as NULL" } else { "left unchanged" }
+            );
+        }
+    }
+}
+
+/// Reads an integer ID, signed or unsigned.
+fn to_id(value: MySqlValue) -> Option<Id> {
+    match value {
+        MySqlValue::Int(int) => Some(int as Id),
+        MySqlValue::UInt(uint) => Some(uint as Id),
+        MySqlValue::Bytes(bytes) => std::str::from_utf8(&bytes).ok()?.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn key_column(database: &Database, table: &TableConfig) -> String {
+    let column_info = schema::get_column_info(database, &table.name);
+    let keys: Vec<&String> = column_info
+        .values()
+        .filter(|info| info.is_primary)
+        .map(|info| &info.name)
+        .collect();
+    match keys.as_slice() {
+        [key] => (*key).clone(),
+        _ => panic!("{}: remap needs a column, the primary key is not a single column", table.name),
+    }
+}
+
+fn query_ids(database: &Database, table: &TableConfig, column: &str) -> Vec<Id> {
+    let mut query = format!("SELECT {} FROM {}", column, table.name);
+    if let Some(cond) = &table.condition {
+        query.push_str(&format!(" WHERE {}", cond));
+    }
+    query.push_str(&format!(" ORDER BY {}", column));
+    let mut conn = database.pool.get_conn().unwrap();
+    let ids: Vec<MySqlValue> = conn
+        .exec_map(query, (), |id: MySqlValue| id)
+        .unwrap_or_else(|err| panic!("{}: unable to read IDs of {}: {}", table.name, column, err));
+    ids.into_iter()
+        .map(|id| to_id(id.clone()).unwrap_or_else(|| panic!("{}: {} holds {:?}, which is not an integer ID", table.name, column, id)))
+        .collect()
+}
+
+fn query_destination_max(database: &Database, table: &TableConfig, column: &str) -> Id {
+    if table.outputs.is_some() {
+        panic!("{}: remapping a split table needs an explicit `by` or `start`", table.name);
+    }
+    let name = table.table_rename.as_ref().unwrap_or(&table.name);
+    let column = table.column_rename.as_ref().and_then(|renames| renames.get(column)).map_or(column, String::as_str);
+    let query = format!("SELECT MAX({}) FROM {}", column, name);
+    let mut conn = database.pool.get_conn().unwrap();
+    let max: Option<MySqlValue> = conn
+        .exec_first(query, ())
+        .unwrap_or_else(|err| panic!("{}: unable to read the destination's highest ID: {}", name, err));
+    max.and_then(to_id).unwrap_or(0)
+}
+
+fn write_mapping(
+    export_path: &Path,
+    encryption: &Option<Arc<Encryption>>,
+    table: &str,
+    ids: &[Id],
+    remap: &TableRemap,
+) {
+    let path = export_path.join(format!("{}-id-map.csv", table));
+    let mut file = encryption::create_file(&path, encryption).expect("Unable to create ID map");
+    writeln!(file, "old_{},new_{}", remap.column, remap.column).expect("Unable to write ID map");
+    for id in ids {
+        if let Some(new) = remap.map(*id) {
+            writeln!(file, "{},{}", id, new).expect("Unable to write ID map");
+        }
+    }
+    file.finish().expect("Unable to write ID map");
+}
+
+/// Chooses new IDs for every table with `remap`, writes each mapping to
+/// `<table>-id-map.csv` under the export path, and finds the columns that
+/// reference remapped IDs: foreign keys in the source schema, and the
+/// `references` of each table.
+///
+/// The offset or first ID picked for a table is kept in the migration state,
+/// so a resumed run maps IDs the same way even after part of the import
+/// raised the destination's highest ID.
+pub fn plan(
+    source: &Database,
+    destination: &Database,
+    table_config: &[TableConfig],
+    state: &StateStore,
+    export_path: &Path,
+    encryption: &Option<Arc<Encryption>>,
+) -> RemapPlan {
+    let mut plan = RemapPlan::default();
+    for table in table_config {
+        let remap = match &table.remap {
+            Some(remap) => remap,
+            None => continue,
+        };
+        let column = remap.column.clone().unwrap_or_else(|| key_column(source, table));
+        let ids = query_ids(source, table, &column);
+        let base = state.remap_base(&table.name).unwrap_or_else(|| {
+            let base = match remap.strategy {
+                RemapStrategy::Offset { by: Some(by) } => by as Id,
+                RemapStrategy::Sequence { start: Some(start) } => start as Id,
+                RemapStrategy::Offset { by: None } => query_destination_max(destination, table, &column),
+                RemapStrategy::Sequence { start: None } => query_destination_max(destination, table, &column) + 1,
+            };
+            state.set_remap_base(&table.name, base);
+            base
+        });
+        let table_remap = match remap.strategy {
+            RemapStrategy::Offset { .. } => TableRemap {
+                column,
+                offset: Some(base),
+                mapping: HashMap::new(),
+            },
+            RemapStrategy::Sequence { .. } => TableRemap {
+                column,
+                offset: None,
+                mapping: ids.iter().zip(base..).map(|(old, new)| (*old, new)).collect(),
+            },
+        };
+        write_mapping(export_path, encryption, &table.name, &ids, &table_remap);
+        println!("Remapped {} IDs of {}", ids.len(), table.name);
+        plan.tables.insert(table.name.clone(), table_remap);
+    }
+    if plan.tables.is_empty() {
+        return plan;
+    }
+
+    for fk in subset::query_foreign_keys(source) {
+        let remapped = plan.tables.get(&fk.parent_table).is_some_and(|remap| {
+            fk.parent_columns.len() == 1 && fk.parent_columns[0] == remap.column
+        });
+        if remapped {
+            plan.references
+                .entry(fk.child_table.clone())
+                .or_default()
+                .push((fk.child_columns[0].clone(), fk.parent_table.clone()));
+        }
+    }
+    for table in table_config {
+        for (column, target) in table.references.iter().flatten() {
+            if !plan.tables.contains_key(target) {
+                panic!("{}.{}: references {}, which is not remapped", table.name, column, target);
+            }
+            let references = plan.references.entry(table.name.clone()).or_default();
+            references.retain(|(existing, _)| existing != column);
+            references.push((column.clone(), target.clone()));
+        }
+    }
+    plan
+}
+
+/// Resolves the columns of an exported table that the plan rewrites: its
+/// own remapped column, whose unmapped IDs are kept, and its references,
+/// whose unmapped IDs are handled as `unmapped` says.
+pub fn get_column_remaps<'a>(
+    plan: &'a RemapPlan,
+    table: &str,
+    columns: &'a [String],
+    unmapped: Unmapped,
+) -> Vec<ColumnRemap<'a>> {
+    let own = plan.tables.get(table).map(|remap| (remap.column.as_str(), remap, Unmapped::Keep));
+    let references = plan
+        .references
+        .get(table)
+        .into_iter()
+        .flatten()
+        .map(|(column, target)| (column.as_str(), &plan.tables[target], unmapped));
+    own.into_iter()
+        .chain(references)
+        .filter_map(|(column, target, unmapped)| {
+            let position = columns.iter().position(|col| col == column)?;
+            Some(ColumnRemap { position, column: &columns[position], target, unmapped })
+        })
+        .collect()
+}
+
+/// Replaces old IDs with new ones. NULL stays NULL, and IDs without a new
+/// value are kept or made NULL and reported, or stop the export.
+pub fn apply(table: &str, values: &mut [String], remaps: &[ColumnRemap], report: &mut RemapReport) {
+    for remap in remaps {
+        let value = &values[remap.position];
+        if value == "NULL" {
+            continue;
+        }
+        if let Some(new) = value.parse::<Id>().ok().and_then(|old| remap.target.map(old)) {
+            values[remap.position] = new.to_string();
+            continue;
+        }
+        match remap.unmapped {
+            Unmapped::Fail => panic!(
+                "{}.{}: {} references a row that was not remapped",
+                table, remap.column, value
+            ),
+            Unmapped::Null => values[remap.position] = "NULL".to_string(),
+            Unmapped::Keep => {}
+        }
+        report.unmapped.entry(remap.column.to_string()).or_insert((0, remap.unmapped == Unmapped::Null)).0 += 1;
+    }
+}
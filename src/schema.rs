@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use mysql::prelude::Queryable;
+use crate::database::Database;
+use crate::numeric::NUMERIC_TYPES;
+use crate::spatial::SPATIAL_TYPES;
+
+/// Column metadata read from INFORMATION_SCHEMA.COLUMNS.
+#[derive(Debug, Clone, Default)]
+pub struct ColumnInfo {
+    pub name: String,
+    pub data_type: String,
+    /// The full type, such as `int(10) unsigned`
+    pub column_type: String,
+    pub character_set: Option<String>,
+    pub is_primary: bool,
+    pub is_unique: bool,
+    pub ordinal_position: u32,
+}
+
+/// How values of a column are rendered in the export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnKind {
+    Text,
+    Json,
+    Spatial,
+    Numeric,
+}
+
+/// Resolves the kind of each exported column, by position.
+pub fn get_column_kinds(
+    columns: &[String],
+    column_info: &HashMap<String, ColumnInfo>,
+) -> Vec<ColumnKind> {
+    columns.iter().map(|column| {
+        match column_info.get(column).map(|info| info.data_type.as_str()) {
+            Some("json") => ColumnKind::Json,
+            Some(data_type) if SPATIAL_TYPES.contains(&data_type) => ColumnKind::Spatial,
+            Some(data_type) if NUMERIC_TYPES.contains(&data_type) => ColumnKind::Numeric,
+            _ => ColumnKind::Text,
+        }
+    }).collect()
+}
+
+/// Returns the metadata of every column in `table`, keyed by column name.
+pub fn get_column_info(
+    database: &Database,
+    table: &str,
+) -> HashMap<String, ColumnInfo> {
+    let query = "SELECT COLUMN_NAME, DATA_TYPE, COLUMN_TYPE, CHARACTER_SET_NAME, COLUMN_KEY, ORDINAL_POSITION FROM INFORMATION_SCHEMA.COLUMNS WHERE TABLE_NAME = ? AND TABLE_SCHEMA = ?";
+    let mut conn = database.pool.get_conn().unwrap();
+    let columns: Vec<ColumnInfo> = conn
+        .exec_map(
+            query,
+            (table, &database.name),
+            |(name, data_type, column_type, character_set, column_key, ordinal_position): (String, String, String, Option<String>, String, u32)| ColumnInfo {
+                name,
+                data_type: data_type.to_lowercase(),
+                column_type: column_type.to_lowercase(),
+                character_set: character_set.map(|charset| charset.to_lowercase()),
+                is_primary: column_key == "PRI",
+                is_unique: column_key == "UNI",
+                ordinal_position,
+            },
+        )
+        .unwrap();
+    columns
+        .into_iter()
+        .map(|column| (column.name.clone(), column))
+        .collect()
+}
@@ -0,0 +1,123 @@
+use std::fs;
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Array, CallFnOptions, Dynamic, Engine, Map, Module, Scope, AST};
+use crate::schema::ColumnKind;
+use crate::tables::TableConfig;
+
+/// Operations one call of `transform` may run before it is stopped.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// A table's Rhai script, compiled once and called for every row.
+pub struct RowScript {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+}
+
+fn sandboxed_engine() -> Engine {
+    let mut engine = Engine::new();
+    // Scripts cannot import modules from disk; the standard engine has no
+    // other access to files, the network or processes.
+    engine.set_module_resolver(DummyModuleResolver::new());
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.set_max_call_levels(32);
+    engine.set_max_expr_depths(64, 64);
+    engine.set_max_string_size(1 << 24);
+    engine.set_max_array_size(100_000);
+    engine.set_max_map_size(100_000);
+    engine
+}
+
+impl RowScript {
+    /// Compiles the table's `script`, running its top-level statements once
+    /// so exported constants such as lookup maps are ready before the
+    /// first row.
+    pub fn load(table: &TableConfig) -> Option<Self> {
+        let path = table.script.as_ref()?;
+        let source = fs::read_to_string(path)
+            .unwrap_or_else(|err| panic!("{}: unable to read script {}: {}", table.name, path, err));
+        let mut engine = sandboxed_engine();
+        let ast = engine
+            .compile(&source)
+            .unwrap_or_else(|err| panic!("{}: script {} does not compile: {}", table.name, path, err));
+        if !ast.iter_functions().any(|function| function.name == "transform" && function.params.len() == 1) {
+            panic!("{}: script {} has no transform(row) function", table.name, path);
+        }
+        // Exported constants become globals visible inside `transform`.
+        let module = Module::eval_ast_as_new(Scope::new(), &ast, &engine)
+            .unwrap_or_else(|err| panic!("{}: script {} failed: {}", table.name, path, err));
+        engine.register_global_module(module.into());
+        Some(Self { engine, ast, scope: Scope::new() })
+    }
+
+    /// Calls `transform(row)` and returns the rows to export in its place.
+    ///
+    /// Integer columns are passed as integers, NULL as `()`, and everything
+    /// else as a string. The function returns a map for one row, `()` to
+    /// drop the row, or an array of maps. Columns a returned map leaves out
+    /// keep the input row's value.
+    pub fn run(
+        &mut self,
+        columns: &[String],
+        kinds: &[ColumnKind],
+        values: Vec<String>,
+    ) -> Result<Vec<Vec<String>>, String> {
+        let mut row = Map::new();
+        for ((column, kind), value) in columns.iter().zip(kinds).zip(&values) {
+            row.insert(column.into(), to_dynamic(value, *kind));
+        }
+        // The top-level statements already ran in `load`.
+        let options = CallFnOptions::new().eval_ast(false);
+        let result: Dynamic = self
+            .engine
+            .call_fn_with_options(options, &mut self.scope, &self.ast, "transform", (row,))
+            .map_err(|err| err.to_string())?;
+        if result.is_unit() {
+            return Ok(Vec::new());
+        }
+        if result.is_map() {
+            return Ok(vec![to_row(columns, &values, result.cast::<Map>())?]);
+        }
+        if result.is_array() {
+            return result
+                .cast::<Array>()
+                .into_iter()
+                .map(|item| match item.try_cast::<Map>() {
+                    Some(map) => to_row(columns, &values, map),
+                    None => Err("transform returned an array with a value that is not a map".to_string()),
+                })
+                .collect();
+        }
+        Err(format!("transform returned {}, expected a map, an array of maps or ()", result.type_name()))
+    }
+}
+
+fn to_dynamic(value: &str, kind: ColumnKind) -> Dynamic {
+    if value == "NULL" {
+        return Dynamic::UNIT;
+    }
+    if kind == ColumnKind::Numeric {
+        if let Ok(int) = value.parse::<i64>() {
+            return Dynamic::from_int(int);
+        }
+    }
+    Dynamic::from(value.to_string())
+}
+
+fn to_row(columns: &[String], input: &[String], map: Map) -> Result<Vec<String>, String> {
+    let mut row = input.to_vec();
+    for (column, value) in map {
+        let position = columns
+            .iter()
+            .position(|col| col.as_str() == column.as_str())
+            .ok_or_else(|| format!("transform returned column {} which is not exported", column))?;
+        row[position] = if value.is_unit() {
+            "NULL".to_string()
+        } else if value.is_bool() {
+            (value.as_bool().unwrap() as u8).to_string()
+        } else {
+            value.to_string()
+        };
+    }
+    Ok(row)
+}
@@ -0,0 +1,41 @@
+/// Spatial data types reported by INFORMATION_SCHEMA.COLUMNS.
+pub const SPATIAL_TYPES: [&str; 9] = [
+    "geometry",
+    "point",
+    "linestring",
+    "polygon",
+    "multipoint",
+    "multilinestring",
+    "multipolygon",
+    "geometrycollection",
+    "geomcollection",
+];
+
+/// Hex-encodes the raw bytes of a spatial value.
+///
+/// MySQL stores geometries as a 4-byte little-endian SRID followed by the
+/// WKB, so the value must not go through text decoding.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+/// Renders a hex-encoded internal geometry as an `ST_GeomFromWKB` call that
+/// restores the original SRID.
+///
+/// Internal geometries keep longitude-latitude order, so a non-zero SRID is
+/// passed with an explicit axis order for geographic reference systems.
+pub fn render(value: &str) -> Option<String> {
+    if value.len() < 10 || !value.len().is_multiple_of(2) || !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let srid_bytes: Vec<u8> = (0..4)
+        .map(|i| u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).unwrap())
+        .collect();
+    let srid = u32::from_le_bytes([srid_bytes[0], srid_bytes[1], srid_bytes[2], srid_bytes[3]]);
+    let wkb = &value[8..];
+    if srid == 0 {
+        Some(format!("ST_GeomFromWKB(X'{}', 0)", wkb))
+    } else {
+        Some(format!("ST_GeomFromWKB(X'{}', {}, 'axis-order=long-lat')", wkb, srid))
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use mysql::Value as MySqlValue;
+use serde::{Deserialize, Serialize};
+use crate::encryption::{self, Encryption};
+use crate::remap::Id;
+
+const STATE_FILE: &str = "migration-state.json";
+
+/// Highest value of an incremental column that has been exported.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Watermark {
+    pub column: String,
+    pub value: String,
+}
+
+/// A primary key value stored exactly, so a resumed export continues from
+/// the same row.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum KeyValue {
+    Null,
+    Bytes(Vec<u8>),
+    Int(i64),
+    UInt(u64),
+    Float(f32),
+    Double(f64),
+    Date(u16, u8, u8, u8, u8, u8, u32),
+    Time(bool, u32, u8, u8, u8, u32),
+}
+
+impl From<&MySqlValue> for KeyValue {
+    fn from(value: &MySqlValue) -> Self {
+        match value.clone() {
+            MySqlValue::NULL => KeyValue::Null,
+            MySqlValue::Bytes(bytes) => KeyValue::Bytes(bytes),
+            MySqlValue::Int(int) => KeyValue::Int(int),
+            MySqlValue::UInt(uint) => KeyValue::UInt(uint),
+            MySqlValue::Float(float) => KeyValue::Float(float),
+            MySqlValue::Double(double) => KeyValue::Double(double),
+            MySqlValue::Date(y, m, d, h, i, s, u) => KeyValue::Date(y, m, d, h, i, s, u),
+            MySqlValue::Time(n, d, h, i, s, u) => KeyValue::Time(n, d, h, i, s, u),
+        }
+    }
+}
+
+impl From<&KeyValue> for MySqlValue {
+    fn from(value: &KeyValue) -> Self {
+        match value.clone() {
+            KeyValue::Null => MySqlValue::NULL,
+            KeyValue::Bytes(bytes) => MySqlValue::Bytes(bytes),
+            KeyValue::Int(int) => MySqlValue::Int(int),
+            KeyValue::UInt(uint) => MySqlValue::UInt(uint),
+            KeyValue::Float(float) => MySqlValue::Float(float),
+            KeyValue::Double(double) => MySqlValue::Double(double),
+            KeyValue::Date(y, m, d, h, i, s, u) => MySqlValue::Date(y, m, d, h, i, s, u),
+            KeyValue::Time(n, d, h, i, s, u) => MySqlValue::Time(n, d, h, i, s, u),
+        }
+    }
+}
+
+/// Export progress of one table.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExportProgress {
+    pub completed: bool,
+    /// Primary key of the last row written before `file_offset`
+    pub last_key: Option<Vec<KeyValue>>,
+    /// Length of the export file at the last checkpoint
+    pub file_offset: u64,
+    /// Upper bound of the incremental range being exported
+    pub watermark_until: Option<String>,
+    /// Rows written before `file_offset`
+    #[serde(default)]
+    pub rows: u64,
+    /// Length of each output's file at the last checkpoint
+    #[serde(default)]
+    pub output_offsets: Vec<u64>,
+}
+
+/// Import progress of one table.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImportProgress {
+    pub completed: bool,
+    /// Lines of the export file that have been executed
+    pub lines: u64,
+}
+
+/// Progress that outlives a single run, stored under the export path.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MigrationState {
+    #[serde(default)]
+    pub watermarks: HashMap<String, Watermark>,
+    /// Watermarks of exported ranges that are not imported yet
+    #[serde(default)]
+    pub pending_watermarks: HashMap<String, Watermark>,
+    #[serde(default)]
+    pub exports: HashMap<String, ExportProgress>,
+    #[serde(default)]
+    pub imports: HashMap<String, ImportProgress>,
+    /// Identifies this run's rows in the destination's import progress table
+    #[serde(default)]
+    pub import_run: Option<String>,
+    /// Start of the run, the value of `now` added columns
+    #[serde(default)]
+    pub run_timestamp: Option<String>,
+    /// Offset or first ID chosen for each remapped table
+    #[serde(default)]
+    pub remap_bases: HashMap<String, Id>,
+}
+
+/// Shared handle to the state file; every update is written to disk
+/// immediately so an interrupted run keeps what it finished.
+pub struct StateStore {
+    path: PathBuf,
+    encryption: Option<Arc<Encryption>>,
+    state: Mutex<MigrationState>,
+}
+
+impl StateStore {
+    pub fn load(export_path: &Path, encryption: &Option<Arc<Encryption>>) -> Self {
+        let path = export_path.join(STATE_FILE);
+        let state: MigrationState = if path.exists() {
+            let mut contents = String::new();
+            encryption::open_file(&path, encryption)
+                .and_then(|mut file| file.read_to_string(&mut contents))
+                .expect("Unable to read migration state");
+            serde_json::from_str(&contents).expect("Failed to parse migration state")
+        } else {
+            MigrationState::default()
+        };
+        Self {
+            path,
+            encryption: encryption.clone(),
+            state: Mutex::new(state),
+        }
+    }
+
+    pub fn watermark(&self, table: &str) -> Option<Watermark> {
+        self.state.lock().unwrap().watermarks.get(table).cloned()
+    }
+
+    pub fn set_watermark(&self, table: &str, watermark: Watermark) {
+        self.update(|state| {
+            state.watermarks.insert(table.to_string(), watermark);
+        });
+    }
+
+    pub fn set_pending_watermark(&self, table: &str, watermark: Watermark) {
+        self.update(|state| {
+            state.pending_watermarks.insert(table.to_string(), watermark);
+        });
+    }
+
+    /// Makes the pending watermark of a table the next run's starting point.
+    pub fn commit_watermark(&self, table: &str) {
+        if !self.state.lock().unwrap().pending_watermarks.contains_key(table) {
+            return;
+        }
+        self.update(|state| {
+            if let Some(watermark) = state.pending_watermarks.remove(table) {
+                state.watermarks.insert(table.to_string(), watermark);
+            }
+        });
+    }
+
+    pub fn export_progress(&self, table: &str) -> Option<ExportProgress> {
+        self.state.lock().unwrap().exports.get(table).cloned()
+    }
+
+    pub fn set_export_progress(&self, table: &str, progress: ExportProgress) {
+        self.update(|state| {
+            state.exports.insert(table.to_string(), progress);
+        });
+    }
+
+    pub fn import_progress(&self, table: &str) -> Option<ImportProgress> {
+        self.state.lock().unwrap().imports.get(table).cloned()
+    }
+
+    pub fn set_import_progress(&self, table: &str, progress: ImportProgress) {
+        self.update(|state| {
+            state.imports.insert(table.to_string(), progress);
+        });
+    }
+
+    /// Returns the identifier of this run's import progress, creating it on
+    /// first use.
+    pub fn import_run(&self) -> String {
+        if let Some(run) = self.state.lock().unwrap().import_run.clone() {
+            return run;
+        }
+        let mut run = String::new();
+        self.update(|state| {
+            run = state.import_run.get_or_insert_with(|| uuid::Uuid::new_v4().simple().to_string()).clone();
+        });
+        run
+    }
+
+    /// Returns when this run started, recording it on first use so a
+    /// resumed run writes the same value.
+    pub fn run_timestamp(&self, now: impl FnOnce() -> String) -> String {
+        if let Some(timestamp) = self.state.lock().unwrap().run_timestamp.clone() {
+            return timestamp;
+        }
+        let mut timestamp = String::new();
+        self.update(|state| {
+            timestamp = state.run_timestamp.get_or_insert_with(now).clone();
+        });
+        timestamp
+    }
+
+    pub fn remap_base(&self, table: &str) -> Option<Id> {
+        self.state.lock().unwrap().remap_bases.get(table).copied()
+    }
+
+    pub fn set_remap_base(&self, table: &str, base: Id) {
+        self.update(|state| {
+            state.remap_bases.insert(table.to_string(), base);
+        });
+    }
+
+    /// Forgets the checkpoints, import run, pending watermarks, run
+    /// timestamp and remapping bases of a previous run, keeping watermarks.
+    pub fn reset_progress(&self) {
+        self.update(|state| {
+            state.pending_watermarks.clear();
+            state.exports.clear();
+            state.imports.clear();
+            state.import_run = None;
+            state.run_timestamp = None;
+            state.remap_bases.clear();
+        });
+    }
+
+    /// Applies `change` and saves the state, replacing the previous file
+    /// only once the new one is completely written.
+    pub fn update<F: FnOnce(&mut MigrationState)>(&self, change: F) {
+        let mut state = self.state.lock().unwrap();
+        change(&mut state);
+        let contents = serde_json::to_string_pretty(&*state).expect("Failed to serialize migration state");
+        let temp_path = self.path.with_extension("json.tmp");
+        {
+            let mut file = encryption::create_file(&temp_path, &self.encryption)
+                .expect("Unable to write migration state");
+            file.write_all(contents.as_bytes()).expect("Unable to write migration state");
+            file.finish().expect("Unable to write migration state");
+        }
+        fs::rename(&temp_path, &self.path).expect("Unable to replace migration state");
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use mysql::prelude::Queryable;
+use mysql::Value as MySqlValue;
+use arguments::Args;
+use crate::arguments;
+use crate::database::Database;
+use crate::mysql_utils::quote_string;
+use crate::numeric;
+use crate::schema;
+use crate::spatial;
+use crate::tables::TableConfig;
+
+/// Tuples matched per query, which keeps the placeholders well below the
+/// prepared statement limit.
+const FETCH_CHUNK: usize = 500;
+
+/// A foreign key between two tables of the source schema.
+#[derive(Debug, Clone)]
+pub struct ForeignKey {
+    pub name: String,
+    pub child_table: String,
+    pub child_columns: Vec<String>,
+    pub parent_table: String,
+    pub parent_columns: Vec<String>,
+}
+
+/// Rows selected from one table, identified by their key columns.
+#[derive(Debug, Default)]
+struct SelectedRows {
+    key_columns: Vec<String>,
+    /// Row key to whether its dependent child rows have been followed
+    rows: HashMap<String, (Vec<MySqlValue>, bool)>,
+}
+
+/// A fetched row, by column name.
+type FetchedRow = HashMap<String, MySqlValue>;
+
+/// Rows added to the subset whose references still have to be followed.
+#[derive(Debug)]
+struct PendingRows {
+    table: String,
+    rows: Vec<FetchedRow>,
+    follow_children: bool,
+}
+
+/// Reads every foreign key of the schema from INFORMATION_SCHEMA.
+pub fn query_foreign_keys(database: &Database) -> Vec<ForeignKey> {
+    let query = "SELECT CONSTRAINT_NAME, TABLE_NAME, COLUMN_NAME, REFERENCED_TABLE_NAME, REFERENCED_COLUMN_NAME \
+        FROM INFORMATION_SCHEMA.KEY_COLUMN_USAGE \
+        WHERE TABLE_SCHEMA = ? AND REFERENCED_TABLE_SCHEMA = TABLE_SCHEMA AND REFERENCED_TABLE_NAME IS NOT NULL \
+        ORDER BY TABLE_NAME, CONSTRAINT_NAME, ORDINAL_POSITION";
+    let mut conn = database.pool.get_conn().unwrap();
+    let columns: Vec<(String, String, String, String, String)> = conn
+        .exec(query, (&database.name,))
+        .unwrap();
+    let mut foreign_keys: Vec<ForeignKey> = Vec::new();
+    for (name, child_table, child_column, parent_table, parent_column) in columns {
+        match foreign_keys.last_mut() {
+            Some(fk) if fk.name == name && fk.child_table == child_table => {
+                fk.child_columns.push(child_column);
+                fk.parent_columns.push(parent_column);
+            }
+            _ => foreign_keys.push(ForeignKey {
+                name,
+                child_table,
+                child_columns: vec![child_column],
+                parent_table,
+                parent_columns: vec![parent_column],
+            }),
+        }
+    }
+    foreign_keys
+}
+
+fn row_key(values: &[MySqlValue]) -> String {
+    format!("{:?}", values)
+}
+
+/// Renders a value as a SQL literal for the generated subset conditions.
+fn to_literal(value: &MySqlValue) -> String {
+    match value {
+        MySqlValue::NULL => "NULL".to_string(),
+        MySqlValue::Bytes(bytes) => match std::str::from_utf8(bytes) {
+            Ok(text) => quote_string(text),
+            Err(_) => format!("X'{}'", spatial::to_hex(bytes)),
+        },
+        MySqlValue::Int(int) => int.to_string(),
+        MySqlValue::UInt(uint) => uint.to_string(),
+        MySqlValue::Float(float) => numeric::format_f32(*float),
+        MySqlValue::Double(double) => numeric::format_f64(*double),
+        other => other.as_sql(true),
+    }
+}
+
+/// Renders `(a, b) IN ((..), (..))` for the given tuples, as one IN-list
+/// per `FETCH_CHUNK` tuples joined by OR, or `FALSE` when there are none.
+fn in_condition(columns: &[String], tuples: &[Vec<MySqlValue>]) -> String {
+    if tuples.is_empty() {
+        return "FALSE".to_string();
+    }
+    let lists: Vec<String> = tuples
+        .chunks(FETCH_CHUNK)
+        .map(|chunk| {
+            let tuples: Vec<String> = chunk
+                .iter()
+                .map(|tuple| format!("({})", tuple.iter().map(to_literal).collect::<Vec<String>>().join(", ")))
+                .collect();
+            format!("({}) IN ({})", columns.join(", "), tuples.join(", "))
+        })
+        .collect();
+    lists.join(" OR ")
+}
+
+/// Fetches the `select` columns of rows whose `match_columns` equal one of
+/// the tuples.
+fn fetch_rows(
+    database: &Database,
+    table: &str,
+    select: &[String],
+    match_columns: &[String],
+    tuples: &[Vec<MySqlValue>],
+) -> Vec<Vec<MySqlValue>> {
+    let mut conn = database.pool.get_conn().unwrap();
+    let mut rows: Vec<Vec<MySqlValue>> = Vec::new();
+    for chunk in tuples.chunks(FETCH_CHUNK) {
+        let placeholders = format!("({})", vec!["?"; match_columns.len()].join(", "));
+        let query = format!(
+            "SELECT {} FROM {} WHERE ({}) IN ({})",
+            select.join(", "),
+            table,
+            match_columns.join(", "),
+            vec![placeholders; chunk.len()].join(", ")
+        );
+        let params: Vec<MySqlValue> = chunk.iter().flatten().cloned().collect();
+        let result: Vec<mysql::Row> = conn.exec(query, params).unwrap();
+        rows.extend(result.into_iter().map(|row| row.unwrap()));
+    }
+    rows
+}
+
+/// Columns that must be read from a table to follow its foreign keys.
+fn needed_columns(
+    table: &str,
+    key_columns: &[String],
+    foreign_keys: &[ForeignKey],
+) -> Vec<String> {
+    let mut columns: Vec<String> = key_columns.to_vec();
+    for fk in foreign_keys {
+        let fk_columns = if fk.child_table == table {
+            &fk.child_columns
+        } else if fk.parent_table == table {
+            &fk.parent_columns
+        } else {
+            continue;
+        };
+        for column in fk_columns {
+            if !columns.contains(column) {
+                columns.push(column.clone());
+            }
+        }
+    }
+    columns
+}
+
+/// Distinct non-NULL tuples of `columns` among the rows.
+fn project(
+    rows: &[FetchedRow],
+    columns: &[String],
+) -> Vec<Vec<MySqlValue>> {
+    let mut seen: HashSet<String> = HashSet::new();
+    rows.iter()
+        .filter_map(|row| {
+            let tuple: Vec<MySqlValue> = columns.iter().map(|column| row[column].clone()).collect();
+            if tuple.contains(&MySqlValue::NULL) || !seen.insert(row_key(&tuple)) {
+                None
+            } else {
+                Some(tuple)
+            }
+        })
+        .collect()
+}
+
+/// Orders tables so that referenced parents are imported before the
+/// tables that reference them. Tables in a cycle keep their given order.
+fn sort_by_dependencies(tables: Vec<TableConfig>, foreign_keys: &[ForeignKey]) -> Vec<TableConfig> {
+    let names: HashSet<String> = tables.iter().map(|table| table.name.clone()).collect();
+    let mut remaining: Vec<TableConfig> = tables;
+    let mut sorted: Vec<TableConfig> = Vec::new();
+    while !remaining.is_empty() {
+        let pending: HashSet<String> = remaining.iter().map(|table| table.name.clone()).collect();
+        let ready = remaining.iter().position(|table| {
+            !foreign_keys.iter().any(|fk| {
+                fk.child_table == table.name
+                    && fk.parent_table != table.name
+                    && names.contains(&fk.parent_table)
+                    && pending.contains(&fk.parent_table)
+            })
+        });
+        sorted.push(remaining.remove(ready.unwrap_or(0)));
+    }
+    sorted
+}
+
+/// Primary key columns of a table in key order.
+fn primary_key_columns(database: &Database, table: &str) -> Vec<String> {
+    let column_info = schema::get_column_info(database, table);
+    let mut keys: Vec<&schema::ColumnInfo> = column_info.values().filter(|info| info.is_primary).collect();
+    keys.sort_by_key(|info| info.ordinal_position);
+    keys.into_iter().map(|info| info.name.clone()).collect()
+}
+
+/// Records fetched rows and queues the ones not seen before, or seen
+/// before without following their children when that is now needed.
+fn add_rows(
+    database: &Database,
+    selected: &mut HashMap<String, SelectedRows>,
+    queue: &mut VecDeque<PendingRows>,
+    table: &str,
+    select: &[String],
+    rows: Vec<Vec<MySqlValue>>,
+    follow_children: bool,
+) {
+    let entry = selected.entry(table.to_string()).or_insert_with(|| {
+        let mut key_columns = primary_key_columns(database, table);
+        // Without a primary key, rows are told apart by the columns read.
+        if key_columns.is_empty() {
+            key_columns = select.to_vec();
+        }
+        SelectedRows { key_columns, rows: HashMap::new() }
+    });
+    let mut new_rows: Vec<FetchedRow> = Vec::new();
+    for values in rows {
+        let row: FetchedRow = select.iter().cloned().zip(values).collect();
+        let key_values: Vec<MySqlValue> = entry.key_columns.iter().map(|column| row[column].clone()).collect();
+        let key = row_key(&key_values);
+        match entry.rows.get_mut(&key) {
+            Some((_, followed)) if *followed || !follow_children => continue,
+            Some((_, followed)) => *followed = true,
+            None => {
+                entry.rows.insert(key, (key_values, follow_children));
+            }
+        }
+        new_rows.push(row);
+    }
+    if !new_rows.is_empty() {
+        queue.push_back(PendingRows {
+            table: table.to_string(),
+            rows: new_rows,
+            follow_children,
+        });
+    }
+}
+
+/// Columns to read from a table: its key and its foreign key columns.
+fn select_columns(
+    database: &Database,
+    selected: &HashMap<String, SelectedRows>,
+    table: &str,
+    foreign_keys: &[ForeignKey],
+) -> Vec<String> {
+    let key_columns: Vec<String> = match selected.get(table) {
+        Some(rows) => rows.key_columns.clone(),
+        None => primary_key_columns(database, table),
+    };
+    needed_columns(table, &key_columns, foreign_keys)
+}
+
+/// Replaces the table list with a referentially complete subset.
+///
+/// Rows of the tables marked `subset_root` that match their `condition`
+/// are the starting point. Every row they reference through a foreign key
+/// is added, recursively. With `--subset-children`, rows that reference
+/// the root rows, and rows that reference those, are added too, together
+/// with their own parents. Each selected table is exported with a
+/// condition listing its selected keys, parents first.
+pub fn extract(
+    database: &Database,
+    args: &Args,
+    table_config: Vec<TableConfig>,
+) -> Vec<TableConfig> {
+    let foreign_keys: Vec<ForeignKey> = query_foreign_keys(database);
+    let mut selected: HashMap<String, SelectedRows> = HashMap::new();
+    let mut queue: VecDeque<PendingRows> = VecDeque::new();
+
+    for root in table_config.iter().filter(|table| table.subset_root == Some(true)) {
+        let select = select_columns(database, &selected, &root.name, &foreign_keys);
+        let mut query = format!("SELECT {} FROM {}", select.join(", "), root.name);
+        if let Some(cond) = &root.condition {
+            query.push_str(&format!(" WHERE {}", cond));
+        }
+        let mut conn = database.pool.get_conn().unwrap();
+        let rows: Vec<mysql::Row> = conn.exec(query, ()).unwrap();
+        let rows: Vec<Vec<MySqlValue>> = rows.into_iter().map(|row| row.unwrap()).collect();
+        add_rows(database, &mut selected, &mut queue, &root.name, &select, rows, args.subset_children);
+    }
+
+    while let Some(pending) = queue.pop_front() {
+        for fk in foreign_keys.iter().filter(|fk| fk.child_table == pending.table) {
+            let tuples = project(&pending.rows, &fk.child_columns);
+            if tuples.is_empty() {
+                continue;
+            }
+            let select = select_columns(database, &selected, &fk.parent_table, &foreign_keys);
+            let parents = fetch_rows(database, &fk.parent_table, &select, &fk.parent_columns, &tuples);
+            add_rows(database, &mut selected, &mut queue, &fk.parent_table, &select, parents, false);
+        }
+        if !pending.follow_children {
+            continue;
+        }
+        for fk in foreign_keys.iter().filter(|fk| fk.parent_table == pending.table) {
+            let tuples = project(&pending.rows, &fk.parent_columns);
+            if tuples.is_empty() {
+                continue;
+            }
+            let select = select_columns(database, &selected, &fk.child_table, &foreign_keys);
+            let children = fetch_rows(database, &fk.child_table, &select, &fk.child_columns, &tuples);
+            add_rows(database, &mut selected, &mut queue, &fk.child_table, &select, children, true);
+        }
+    }
+
+    let mut subset: Vec<TableConfig> = Vec::new();
+    let mut names: Vec<&String> = selected.keys().collect();
+    names.sort();
+    for name in names {
+        let rows = &selected[name];
+        let mut keys: Vec<Vec<MySqlValue>> = rows.rows.values().map(|(key, _)| key.clone()).collect();
+        keys.sort_by_key(|key| row_key(key));
+        let mut table = table_config
+            .iter()
+            .find(|table| &table.name == name)
+            .cloned()
+            .unwrap_or_else(|| TableConfig {
+                name: name.clone(),
+                ..Default::default()
+            });
+        table.condition = Some(in_condition(&rows.key_columns, &keys));
+        println!("Subset of {}: {} rows", name, keys.len());
+        subset.push(table);
+    }
+    sort_by_dependencies(subset, &foreign_keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns() -> Vec<String> {
+        vec!["id".to_string()]
+    }
+
+    #[test]
+    fn matches_nothing_without_keys() {
+        assert_eq!(in_condition(&columns(), &[]), "FALSE");
+    }
+
+    #[test]
+    fn lists_the_keys() {
+        let keys = vec![vec![MySqlValue::Int(1)], vec![MySqlValue::Bytes(b"a'b".to_vec())]];
+        assert_eq!(in_condition(&columns(), &keys), "(id) IN ((1), (_utf8mb4'a\\'b'))");
+    }
+
+    #[test]
+    fn splits_long_lists() {
+        let keys: Vec<Vec<MySqlValue>> = (0..FETCH_CHUNK as i64 + 1).map(|id| vec![MySqlValue::Int(id)]).collect();
+        let condition = in_condition(&columns(), &keys);
+        assert_eq!(condition.matches(" IN (").count(), 2);
+        assert!(condition.ends_with(&format!(" OR (id) IN (({}))", FETCH_CHUNK)));
+    }
+}
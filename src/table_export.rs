@@ -1,29 +1,146 @@
+use crate::add_columns::{self, AddedColumn};
 use crate::arguments::Args;
+use crate::cast::{self, CastReport, ColumnCast};
+use crate::charset;
+use crate::column_mapping;
+use crate::dedupe;
 use crate::database::Database;
-use crate::tables::{Override, TableConfig};
+use crate::encryption::{self, ArtifactWriter, Encryption};
+use crate::incremental::{self, WatermarkRange};
+use crate::json::{self, JsonReport};
+use crate::mask::{MaskKeys, Masker};
+use crate::remap::{self, ColumnRemap, RemapPlan, RemapReport};
+use crate::overrides::{self, RowFilter, ValueOverride};
+use crate::tables::TableConfig;
 use mysql::prelude::Queryable;
-use mysql::Row;
+use mysql::{Binary, PooledConn, QueryResult, Row};
+use mysql::Value as MySqlValue;
 use tokio::task;
-use std::io::BufWriter;
-use std::path::PathBuf;
-use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::io::Write;
 use std::collections::HashMap;
 use std::sync::Arc;
-use crate::mysql_utils::to_vector_string;
+use crate::mysql_utils::{escape_string, json_to_string, quote_string, to_vector_string, value_to_string};
+use crate::schema::{self, ColumnInfo, ColumnKind};
+use crate::script::RowScript;
+use crate::numeric;
+use crate::spatial;
+use crate::state::{ExportProgress, KeyValue, StateStore};
+use crate::timezone::{self, TimezoneReport};
+use crate::transform::{self, ColumnTransform};
+/// Counts the bytes written to an export file, so checkpoints can record
+/// how much of it is complete.
+pub struct CountingWriter {
+    inner: Box<dyn ArtifactWriter>,
+    count: u64,
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+type ExportWriter = BufWriter<CountingWriter>;
+
+/// A destination table the exported rows are written to, with the
+/// extended insert it is building.
+struct Output {
+    name: String,
+    /// Positions of the output's columns in the row, or every column
+    positions: Option<Vec<usize>>,
+    columns: Vec<String>,
+    kinds: Vec<ColumnKind>,
+    filter: Option<RowFilter>,
+    writer: ExportWriter,
+    insert_statement: String,
+    row_count: usize,
+    /// Ends every insert, making it update an existing row for incremental
+    /// tables
+    on_duplicate: String,
+}
+
+impl Output {
+    fn write(&mut self, row: &[String], args: &Args, insert_prefix: &str, complete_insert: bool) {
+        if self.filter.as_ref().is_some_and(|filter| !filter.matches(row)) {
+            return;
+        }
+        let selected: Vec<String>;
+        let values: &[String] = match &self.positions {
+            Some(positions) => {
+                selected = positions.iter().map(|position| row[*position].clone()).collect();
+                &selected
+            }
+            None => row,
+        };
+        if args.extended_insert {
+            handle_extended_insert(
+                &mut self.insert_statement,
+                &mut self.row_count,
+                args.extended_insert_limit,
+                values,
+                &self.kinds,
+                &self.columns,
+                insert_prefix,
+                &self.name,
+                &mut self.writer,
+                complete_insert,
+                &self.on_duplicate
+            );
+        } else {
+            handle_regular_insert(
+                values,
+                &self.kinds,
+                &self.columns,
+                insert_prefix,
+                &self.name,
+                &mut self.writer,
+                complete_insert,
+                &self.on_duplicate
+            );
+        }
+    }
+
+    fn flush(&mut self) {
+        flush_extended_insert(&mut self.insert_statement, &mut self.row_count, &mut self.writer, &self.on_duplicate);
+        self.writer.flush().expect("Unable to write to file");
+    }
+
+    /// Writes out the rest of the file, sealing it when it is encrypted.
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_mut().inner.finish()
+    }
+}
+
+/// Exports a table in its own task, which returns whether the export
+/// finished.
+#[allow(clippy::too_many_arguments)]
 pub fn export(
     args: Arc<Args>,
     database: Arc<Database>, 
     table: Arc<TableConfig>, 
     export_path: Arc<PathBuf>,
-) -> task::JoinHandle<()> {
+    encryption: Option<Arc<Encryption>>,
+    state: Arc<StateStore>,
+    mask_keys: Arc<MaskKeys>,
+    remap_plan: Arc<RemapPlan>,
+) -> task::JoinHandle<bool> {
     let insert_prefix = if args.insert_ignore {
         "INSERT IGNORE INTO"
     } else {
         "INSERT INTO"
     };
     
-    let task: task::JoinHandle<()> = task::spawn({
+    let task: task::JoinHandle<bool> = task::spawn({
         // Clone the Arcs to move into the async block
         let args = Arc::clone(&args);
         let database = Arc::clone(&database);
@@ -31,58 +148,308 @@ pub fn export(
         let export_path = Arc::clone(&export_path);
 
         async move {
+            let progress: Option<ExportProgress> = if args.resume {
+                state.export_progress(&table.name)
+            } else {
+                None
+            };
+            if progress.as_ref().is_some_and(|progress| progress.completed) {
+                println!("Skipping {}: already exported", table.name);
+                return true;
+            }
             let columns: Vec<String> = get_columns(&database, &table);
-            let file_name = get_file_name(&export_path, &table.name, &table.table_rename);
+            let column_info = schema::get_column_info(&database, &table.name);
+            let conversions = timezone::get_conversions(&args, &table, &columns, &column_info);
+            let mut timezone_report = TimezoneReport::default();
+            let charsets = charset::get_column_charsets(
+                &table.name,
+                &columns,
+                &column_info,
+                table.repair_mojibake.unwrap_or(args.repair_mojibake)
+            );
+            let source_kinds = schema::get_column_kinds(&columns, &column_info);
+            let casts: Vec<ColumnCast> = cast::get_casts(&table.name, &columns, &table.cast);
+            let mut cast_report = CastReport::new(&export_path, &table.file_stem(), &encryption);
+            let kinds = cast::get_kinds(&source_kinds, &casts);
+            let value_overrides: Vec<ValueOverride> = overrides::get_overrides(&table.name, &columns, &table.overrides);
+            let json_rewrites = json::get_rewrites(&table.name, &columns, &table.overrides);
+            let mut json_report = JsonReport::new(&export_path, &table.file_stem(), &encryption);
+            let transforms: Vec<ColumnTransform> = transform::get_transforms(&table.name, &columns, &table.transform);
+            let mut script: Option<RowScript> = RowScript::load(&table);
+            let column_remaps: Vec<ColumnRemap> = remap::get_column_remaps(&remap_plan, &table.name, &columns, args.unmapped_references);
+            let mut remap_report = RemapReport::default();
+            let key_columns: Vec<String> = get_key_columns(&columns, &column_info);
+            let key_positions: Vec<usize> = key_columns
+                .iter()
+                .map(|key| columns.iter().position(|col| col == key).unwrap())
+                .collect();
+            let (mut deduper, order_by) = match dedupe::get_deduper(&table, &columns, &charsets, &key_columns, &export_path, &encryption) {
+                Some((deduper, order_by)) => (Some(deduper), order_by),
+                None => (None, Vec::new()),
+            };
+            let added_columns: Vec<AddedColumn> = add_columns::get_added_columns(
+                &args,
+                &state,
+                &table.name,
+                &columns,
+                &kinds,
+                &table.add_columns
+            );
+            let destination_columns: Vec<String> = column_mapping::get_destination_columns(
+                &table,
+                &columns,
+                &table.column_rename
+            );
+            let (insert_columns, insert_kinds, discriminators) = get_insert_columns(
+                &table,
+                &destination_columns,
+                &kinds,
+                &added_columns
+            );
+            let complete_insert = args.complete_insert
+                || table.column_rename.is_some()
+                || !added_columns.is_empty()
+                || !table.discriminators.is_empty()
+                || table.outputs.is_some()
+                || table.union_member;
+            let output_count = table.outputs.as_ref().map_or(1, Vec::len);
 
-            let mut writer = BufWriter::new(File::create(&file_name).expect("Unable to create file"));
+            let mut masker = Masker::new(&table, &columns, &column_info, mask_keys);
+            // A partial table continues after its last checkpoint only when
+            // rows are read in key order, the files can be appended to, and
+            // no masked value has to differ from the ones already written.
+            let resume_from: Option<ExportProgress> = progress.filter(|progress| {
+                progress.last_key.is_some()
+                    && !key_columns.is_empty()
+                    && table.dedupe.is_none()
+                    && !masker.checks_unique()
+                    && encryption.is_none()
+                    && (output_count == 1 || progress.output_offsets.len() == output_count)
+            });
+            let offsets: Option<Vec<u64>> = resume_from.as_ref().map(|progress| {
+                println!("Resuming {} from its last checkpoint", table.name);
+                if progress.output_offsets.is_empty() {
+                    vec![progress.file_offset]
+                } else {
+                    progress.output_offsets.clone()
+                }
+            });
+            let mut outputs: Vec<Output> = open_outputs(
+                &export_path,
+                &table,
+                &insert_columns,
+                &insert_kinds,
+                &offsets,
+                &encryption
+            );
             
-            match query_data(&database, &table.name, &columns, &table.condition) {
-                Ok(rows) => {
-                    let mut row_count = 0;
-                    // Use a for loop to iterate over the rows
-                    for row in rows {
-                        let mut values = to_vector_string(row);
-                        apply_overrides(&mut values, &columns, &table.overrides);
-                        rename_columns(&mut columns.clone(), &table.column_rename);
-                        if args.extended_insert {
-                            handle_extended_insert(
-                                &mut row_count,
-                                args.extended_insert_limit,
-                                &values,
-                                &columns,
-                                insert_prefix,
-                                &table,
-                                &mut writer,
-                                args.complete_insert
-                            );
-                        } else {
-                            handle_regular_insert(
-                                &values,
-                                &columns,
-                                insert_prefix,
-                                &table,
-                                &mut writer,
-                                args.complete_insert
-                            );
+            let mut range: Option<WatermarkRange> = match incremental::get_range(&database, &table, &state) {
+                Ok(range) => range,
+                Err(err) => {
+                    eprintln!("Error reading watermark of {}: {:?}", table.name, err);
+                    return false;
+                }
+            };
+            if let (Some(range), Some(progress)) = (range.as_mut(), &resume_from) {
+                range.until = progress.watermark_until.clone();
+            }
+
+            for position in masker.shuffled_positions() {
+                let mut conn = database.pool.get_conn().unwrap();
+                let pool = match query_data(
+                    &mut conn,
+                    &table.name,
+                    &columns[position..=position],
+                    &table.condition,
+                    &range,
+                    &[],
+                    &None,
+                    None,
+                    &[]
+                ).and_then(|rows| rows.collect::<Result<Vec<Row>, mysql::Error>>()) {
+                    Ok(rows) => rows,
+                    Err(err) => {
+                        eprintln!("Error fetching values to shuffle: {:?}", err);
+                        return false;
+                    }
+                };
+                let pool: Vec<String> = pool.into_iter().map(|row| {
+                    let mut raw = row.unwrap();
+                    timezone::convert_row(
+                        &mut raw,
+                        &columns[position..=position],
+                        &conversions[position..=position],
+                        args.timezone_ambiguity,
+                        &mut TimezoneReport::default()
+                    );
+                    value_to_string(raw.remove(0), &charsets[position])
+                }).collect();
+                masker.set_pool(&table.name, position, pool);
+            }
+            let mut row_index: u64 = resume_from.as_ref().map_or(0, |progress| progress.rows);
+
+            let mut last_key: Option<Vec<MySqlValue>> = resume_from
+                .and_then(|progress| progress.last_key)
+                .map(|key| key.iter().map(MySqlValue::from).collect());
+            // Deduplicated tables are read in one query sorted by their key.
+            let batch_size: Option<usize> = if key_columns.is_empty() || deduper.is_some() {
+                None
+            } else {
+                Some(args.checkpoint_rows as usize)
+            };
+            loop {
+                let mut conn = database.pool.get_conn().unwrap();
+                let rows = match query_data(
+                    &mut conn,
+                    &table.name,
+                    &columns,
+                    &table.condition,
+                    &range,
+                    &key_columns,
+                    &last_key,
+                    batch_size,
+                    &order_by
+                ) {
+                    Ok(rows) => rows,
+                    Err(err) => {
+                        eprintln!("Error fetching rows: {:?}", err);
+                        return false;
+                    }
+                };
+                let mut fetched = 0;
+                for row in rows {
+                    let mut raw = match row {
+                        Ok(row) => row.unwrap(),
+                        Err(err) => {
+                            eprintln!("Error fetching rows: {:?}", err);
+                            return false;
+                        }
+                    };
+                    fetched += 1;
+                    let dedupe_key: Option<Vec<MySqlValue>> = deduper.as_ref().map(|deduper| deduper.key(&raw));
+                    if let (Some(deduper), Some(key)) = (deduper.as_mut(), &dedupe_key) {
+                        if deduper.is_duplicate(key) {
+                            let values: Vec<String> = raw
+                                .into_iter()
+                                .zip(&charsets)
+                                .map(|(value, charset)| value_to_string(value, charset))
+                                .collect();
+                            deduper.record(&values, &describe_row(&values, &key_positions));
+                            continue;
+                        }
+                    }
+                    if !key_positions.is_empty() {
+                        last_key = Some(key_positions.iter().map(|pos| raw[*pos].clone()).collect());
+                    }
+                    timezone::convert_row(
+                        &mut raw,
+                        &columns,
+                        &conversions,
+                        args.timezone_ambiguity,
+                        &mut timezone_report
+                    );
+                    let mut values = to_vector_string(raw, &charsets, &source_kinds);
+                    if let Err(errors) = cast::apply(&mut values, &casts, &mut cast_report) {
+                        cast_report.reject(&describe_row(&values, &key_positions), &errors);
+                        continue;
+                    }
+                    if let (Some(deduper), Some(key)) = (deduper.as_mut(), dedupe_key) {
+                        deduper.keep(key);
+                    }
+                    transform::apply(&mut values, &transforms);
+                    let output_rows: Vec<Vec<String>> = match script.as_mut() {
+                        Some(script) => match script.run(&columns, &kinds, values) {
+                            Ok(output_rows) => output_rows,
+                            Err(err) => {
+                                eprintln!("Error running script of {}: {}", table.name, err);
+                                return false;
+                            }
+                        },
+                        None => vec![values],
+                    };
+                    for mut values in output_rows {
+                        overrides::apply(&mut values, &value_overrides);
+                        remap::apply(&table.name, &mut values, &column_remaps, &mut remap_report);
+                        if !masker.is_empty() {
+                            masker.apply(&mut values, row_index);
+                        }
+                        row_index += 1;
+                        let invalid_json = json::process_row(&mut values, &columns, &kinds, &json_rewrites);
+                        if !invalid_json.is_empty() {
+                            let reject = table.reject_invalid_json.unwrap_or(false);
+                            json_report.record(&describe_row(&values, &key_positions), &invalid_json, reject);
+                            if reject {
+                                continue;
+                            }
+                        }
+                        add_columns::apply(&mut values, &added_columns);
+                        for (position, value) in &discriminators {
+                            if *position < values.len() {
+                                values[*position] = value.clone();
+                            } else {
+                                values.push(value.clone());
+                            }
+                        }
+                        for output in outputs.iter_mut() {
+                            output.write(&values, &args, insert_prefix, complete_insert);
                         }
                     }
                 }
-                Err(err) => eprintln!("Error fetching rows: {:?}", err),
+                for output in outputs.iter_mut() {
+                    output.flush();
+                }
+                match batch_size {
+                    Some(batch_size) if fetched >= batch_size => {
+                        state.set_export_progress(&table.name, ExportProgress {
+                            completed: false,
+                            last_key: last_key
+                                .as_ref()
+                                .map(|key| key.iter().map(KeyValue::from).collect()),
+                            file_offset: outputs[0].writer.get_ref().count,
+                            watermark_until: range.as_ref().and_then(|range| range.until.clone()),
+                            rows: row_index,
+                            output_offsets: outputs.iter().map(|output| output.writer.get_ref().count).collect(),
+                        });
+                    }
+                    _ => break,
+                }
+            }
+            for output in outputs.iter_mut() {
+                if let Err(err) = output.finish() {
+                    eprintln!("{}: unable to finish the export file of {}: {}", table.name, output.name, err);
+                    return false;
+                }
+            }
+            if let Some(range) = &range {
+                incremental::finish_range(&args, &state, &table, range);
             }
+            state.set_export_progress(&table.name, ExportProgress {
+                completed: true,
+                rows: row_index,
+                ..Default::default()
+            });
 
+            timezone_report.print(&table.name, args.timezone_ambiguity);
+            json_report.print(&table.name);
+            cast_report.print(&table.name);
+            if let Some(deduper) = deduper.as_mut() {
+                deduper.print(&table.name);
+            }
+            remap_report.print(&table.name);
             println!("Exported {}", table.name);
+            true
         }
     });
     task
 }
 
-fn get_columns(
+pub fn get_columns(
     database: &Database,
     table: &TableConfig,
 ) -> Vec<String> {
     match &table.columns {
         Some(cols) if !(cols.len() == 1 && cols[0] == "*") => cols.clone(),
-        _ => query_columns(&database, &table.name),
+        _ => query_columns(database, &table.name),
     }
 }
 
@@ -99,24 +466,201 @@ fn query_columns(
     conn.query_map(query, |column_name: String| column_name).unwrap()
 }
 
-fn query_data(
-    database: &Database,
+/// Primary key columns of the table, or none if any of them is not
+/// exported, in which case the table is read in a single query.
+fn get_key_columns(
+    columns: &[String],
+    column_info: &HashMap<String, ColumnInfo>,
+) -> Vec<String> {
+    let mut keys: Vec<&ColumnInfo> = column_info
+        .values()
+        .filter(|info| info.is_primary)
+        .collect();
+    keys.sort_by_key(|info| info.ordinal_position);
+    if keys.iter().all(|info| columns.contains(&info.name)) {
+        keys.into_iter().map(|info| info.name.clone()).collect()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Opens an export file for appending, dropping anything written after
+/// the last checkpoint.
+fn reopen_file(path: &Path, offset: u64) -> io::Result<std::fs::File> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(offset)?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(file)
+}
+
+/// Reads the rows of a table, in batches ordered by primary key when
+/// `batch_size` is set, starting after `last_key`, or in `order_by` order
+/// otherwise. Rows are streamed from the server as they are iterated.
+#[allow(clippy::too_many_arguments)]
+fn query_data<'a>(
+    conn: &'a mut PooledConn,
     table: &str,
-    columns: &Vec<String>,
+    columns: &[String],
     condition: &Option<String>,
-) -> Result<Vec<Row>, mysql::Error> {
+    range: &Option<WatermarkRange>,
+    key_columns: &[String],
+    last_key: &Option<Vec<MySqlValue>>,
+    batch_size: Option<usize>,
+    order_by: &[String],
+) -> Result<QueryResult<'a, 'a, 'a, Binary>, mysql::Error> {
     let mut query = format!("SELECT {} FROM {}", columns.join(", "), table);
+    let mut params: Vec<mysql::Value> = Vec::new();
+    let mut conditions: Vec<String> = Vec::new();
     if let Some(cond) = condition {
-        query.push_str(&format!(" WHERE {}", cond));
+        conditions.push(format!("({})", cond));
+    }
+    if let Some(range) = range {
+        let (range_condition, range_params) = range.condition();
+        conditions.push(range_condition);
+        params.extend(range_params);
+    }
+    if let (Some(_), Some(last_key)) = (batch_size, last_key) {
+        let placeholders = vec!["?"; key_columns.len()].join(", ");
+        conditions.push(format!("({}) > ({})", key_columns.join(", "), placeholders));
+        params.extend(last_key.iter().cloned());
+    }
+    if !conditions.is_empty() {
+        query.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+    }
+    if let Some(batch_size) = batch_size {
+        query.push_str(&format!(" ORDER BY {} LIMIT {}", key_columns.join(", "), batch_size));
+    } else if !order_by.is_empty() {
+        query.push_str(&format!(" ORDER BY {}", order_by.join(", ")));
     }
     
-    let mut conn = database.pool.get_conn().unwrap();
-    let rows: Vec<Row> = conn.query(query)?;
-    Ok(rows)
+    // The binary protocol returns typed values, so dates arrive as
+    // `MySqlValue::Date` rather than as text.
+    conn.exec_iter(query, params)
+}
+
+/// Opens the file of every destination table: the table itself, or each of
+/// its `outputs`. `offsets` gives the length of each file at the last
+/// checkpoint when resuming.
+fn open_outputs(
+    export_path: &Path,
+    table: &TableConfig,
+    columns: &[String],
+    kinds: &[ColumnKind],
+    offsets: &Option<Vec<u64>>,
+    encryption: &Option<Arc<Encryption>>,
+) -> Vec<Output> {
+    let mut outputs: Vec<Output> = Vec::new();
+    let open = |index: usize, file_name: String| -> ExportWriter {
+        let (file, file_offset): (Box<dyn ArtifactWriter>, u64) = match offsets {
+            Some(offsets) => {
+                let file = reopen_file(Path::new(&file_name), offsets[index])
+                    .expect("Unable to reopen file");
+                (Box::new(file), offsets[index])
+            }
+            None => {
+                let file = encryption::create_file(Path::new(&file_name), encryption)
+                    .expect("Unable to create file");
+                (file, 0)
+            }
+        };
+        BufWriter::new(CountingWriter {
+            inner: file,
+            count: file_offset,
+        })
+    };
+    let table_outputs = match &table.outputs {
+        Some(table_outputs) => table_outputs,
+        None => {
+            outputs.push(Output {
+                name: table.table_rename.clone().unwrap_or_else(|| table.name.clone()),
+                positions: None,
+                columns: columns.to_vec(),
+                kinds: kinds.to_vec(),
+                filter: None,
+                writer: open(0, get_file_name(export_path, &table.file_stem(), &None)),
+                insert_statement: String::new(),
+                row_count: 0,
+                on_duplicate: on_duplicate_clause(table, columns),
+            });
+            return outputs;
+        }
+    };
+    for (index, output) in table_outputs.iter().enumerate() {
+        let mut selected: Vec<String> = output.columns.clone().unwrap_or_else(|| columns.to_vec());
+        // Every output carries the discriminators.
+        for discriminator in &table.discriminators {
+            if !selected.contains(&discriminator.column) {
+                selected.push(discriminator.column.clone());
+            }
+        }
+        let positions: Vec<usize> = selected.iter().map(|column| {
+            columns
+                .iter()
+                .position(|col| col == column)
+                .unwrap_or_else(|| panic!("{}: output {} column {} is not exported", table.name, output.name, column))
+        }).collect();
+        let output_columns = column_mapping::get_destination_columns(table, &selected, &output.column_rename);
+        outputs.push(Output {
+            on_duplicate: on_duplicate_clause(table, &output_columns),
+            name: output.name.clone(),
+            kinds: positions.iter().map(|position| kinds[*position]).collect(),
+            positions: Some(positions),
+            columns: output_columns,
+            filter: output.filter.as_ref().map(|filter| overrides::get_filter(&table.name, columns, filter)),
+            writer: open(index, get_file_name(export_path, &output.name, &None)),
+            insert_statement: String::new(),
+            row_count: 0,
+        });
+    }
+    outputs
+}
+
+/// Columns and kinds of the insert statements: the exported columns, then
+/// the added ones. Also returns the position and value of each
+/// discriminator column, which replaces a column of the same name or is
+/// added after the others.
+fn get_insert_columns(
+    table: &TableConfig,
+    columns: &[String],
+    kinds: &[ColumnKind],
+    added_columns: &[AddedColumn],
+) -> (Vec<String>, Vec<ColumnKind>, Vec<(usize, String)>) {
+    let mut insert_columns = columns.to_vec();
+    let mut insert_kinds = kinds.to_vec();
+    for column in added_columns {
+        insert_columns.push(column.name.clone());
+        insert_kinds.push(column.kind);
+    }
+    let discriminators = table.discriminators.iter().map(|discriminator| {
+        let value = json_to_string(&discriminator.value);
+        match insert_columns.iter().position(|col| *col == discriminator.column) {
+            Some(position) => (position, value),
+            None => {
+                insert_columns.push(discriminator.column.clone());
+                insert_kinds.push(if discriminator.value.is_number() || discriminator.value.is_boolean() {
+                    ColumnKind::Numeric
+                } else {
+                    ColumnKind::Text
+                });
+                (insert_columns.len() - 1, value)
+            }
+        }
+    }).collect();
+    (insert_columns, insert_kinds, discriminators)
+}
+
+/// Identifies a row in a report: its primary key, or all its values.
+fn describe_row(values: &[String], key_positions: &[usize]) -> String {
+    let row: Vec<&String> = if key_positions.is_empty() {
+        values.iter().collect()
+    } else {
+        key_positions.iter().map(|position| &values[*position]).collect()
+    };
+    format!("{:?}", row)
 }
 
 fn get_file_name(
-    export_path: &PathBuf,
+    export_path: &Path,
     table_name: &String,
     table_rename: &Option<String>,
 ) -> String {
@@ -126,24 +670,27 @@ fn get_file_name(
         None => format!("{}/{}.sql", path, table_name),
     }
 }
+#[allow(clippy::too_many_arguments)]
 fn handle_extended_insert(
+    insert_statement: &mut String,
     row_count: &mut usize,
     extended_insert_limit: usize,
-    values: &Vec<String>,
-    columns: &Vec<String>,
+    values: &[String],
+    kinds: &[ColumnKind],
+    columns: &[String],
     insert_prefix: &str,
-    table: &TableConfig,
-    writer: &mut BufWriter<File>,
-    complete_insert: bool
+    destination: &str,
+    writer: &mut ExportWriter,
+    complete_insert: bool,
+    on_duplicate: &str
 ) {
-    let mut insert_statement = String::new();
     let column_list = columns_to_str(columns, complete_insert);
-    let value_list = values_to_str(values);
+    let value_list = values_to_str(values, kinds);
     if *row_count == 0 {
         insert_statement.push_str(&format!(
             "{} {} {} VALUES",
             insert_prefix,
-            table.table_rename.as_ref().unwrap_or(&table.name),
+            destination,
             column_list
         ));
     } else {
@@ -153,34 +700,62 @@ fn handle_extended_insert(
     *row_count += 1;
     
     if *row_count >= extended_insert_limit {
-        insert_statement.push(';');
-        writeln!(writer, "{}", insert_statement).expect("Unable to write to file");
-        insert_statement.clear();
-        *row_count = 0;
+        flush_extended_insert(insert_statement, row_count, writer, on_duplicate);
+    }
+}
+
+/// Writes the pending extended insert, if any rows have been added to it.
+fn flush_extended_insert(
+    insert_statement: &mut String,
+    row_count: &mut usize,
+    writer: &mut ExportWriter,
+    on_duplicate: &str,
+) {
+    if *row_count == 0 {
+        return;
     }
+    insert_statement.push_str(on_duplicate);
+    insert_statement.push(';');
+    writeln!(writer, "{}", insert_statement).expect("Unable to write to file");
+    insert_statement.clear();
+    *row_count = 0;
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handle_regular_insert(
-    values: &Vec<String>,
-    columns: &Vec<String>,
+    values: &[String],
+    kinds: &[ColumnKind],
+    columns: &[String],
     insert_prefix: &str,
-    table: &TableConfig,
-    writer: &mut BufWriter<File>,
-    complete_insert: bool
+    destination: &str,
+    writer: &mut ExportWriter,
+    complete_insert: bool,
+    on_duplicate: &str
 ) {
     let column_list = columns_to_str(columns, complete_insert);
-    let value_list = values_to_str(values);
+    let value_list = values_to_str(values, kinds);
     writeln!(
         writer,
-        "{} {} {} VALUES ({});",
+        "{} {} {} VALUES ({}){};",
         insert_prefix,
-        table.table_rename.as_ref().unwrap_or(&table.name),
+        destination,
         column_list,
-        value_list
+        value_list,
+        on_duplicate
     ).expect("Unable to write to file");
 }
 
-fn columns_to_str(columns: &Vec<String>, complete_insert: bool) -> String {
+/// Makes the inserts of an incremental table update the row with the same
+/// key, since rows updated after the previous run are exported again.
+fn on_duplicate_clause(table: &TableConfig, columns: &[String]) -> String {
+    if table.incremental.is_none() {
+        return String::new();
+    }
+    let updates: Vec<String> = columns.iter().map(|column| format!("`{0}` = VALUES(`{0}`)", column)).collect();
+    format!(" ON DUPLICATE KEY UPDATE {}", updates.join(", "))
+}
+
+fn columns_to_str(columns: &[String], complete_insert: bool) -> String {
     if complete_insert {
         write_column_query(columns)
     } else {
@@ -188,57 +763,27 @@ fn columns_to_str(columns: &Vec<String>, complete_insert: bool) -> String {
     }
 }
 
-fn values_to_str(values: &Vec<String>) -> String {
-    values.iter().map(|value| {
+fn values_to_str(values: &[String], kinds: &[ColumnKind]) -> String {
+    values.iter().zip(kinds).map(|(value, kind)| {
         if value == "NULL" || value == "0000-00-00" || value == "0000-00-00 00:00:00" {
             "NULL".to_string()
         } else {
-            format!("\"{}\"", value)
-        }
-    }).collect::<Vec<String>>().join(", ")
-}
-
-fn apply_overrides(
-    values: &mut Vec<String>,
-    columns: &Vec<String>,
-    overrides: &Option<Vec<Override>>,
-) {
-    if let Some(overrides) = overrides {
-        for o in overrides {
-            match columns.iter().position(|col| col == &o.name) {
-                Some(pos) => {
-                    if values[pos] == o.value {
-                        o.set.iter().for_each(|(o_column, val)| {
-                            if let Some(set_position) = columns.iter().position(|col| col == o_column) {
-                                values[set_position] = val.clone();
-                            }
-                        });
-                    }
-                }
-                _ => (),
-            }
-        }
-    }
-}
-
-fn rename_columns(
-    columns: &mut Vec<String>,
-    column_rename: &Option<HashMap<String, String>>
-) {
-    if let Some(rename_map) = column_rename {
-        for (old_name, new_name) in rename_map {
-            if let Some(pos) = columns.iter().position(|col| col == old_name) {
-                columns[pos] = new_name.clone();
+            match kind {
+                ColumnKind::Json => format!("CAST({} AS JSON)", quote_string(value)),
+                ColumnKind::Spatial => spatial::render(value).unwrap_or_else(|| "NULL".to_string()),
+                ColumnKind::Numeric if numeric::is_numeric_literal(value) => value.clone(),
+                ColumnKind::Numeric => format!("\"{}\"", escape_string(value)),
+                ColumnKind::Text => format!("\"{}\"", escape_string(value)),
             }
         }
-    }
+    }).collect::<Vec<String>>().join(", ")
 }
 
-fn write_column_query(columns: &Vec<String>) -> String {
-    let mut c = columns.clone();
+fn write_column_query(columns: &[String]) -> String {
+    let mut c = columns.to_vec();
     c.iter_mut().for_each(|s| {
         s.insert(0, '`');
         s.push('`');
     });
-    return format!("({})", c.join(", "));
+    format!("({})", c.join(", "))
 }
\ No newline at end of file
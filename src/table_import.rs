@@ -1,95 +1,479 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
 use std::sync::{Arc, Mutex};
+use crate::arguments::Args;
 use crate::database::Database;
+use crate::encryption::{self, Encryption};
+use crate::state::{ImportProgress, StateStore};
 use crate::tables::TableConfig;
 use std::path::{Path, PathBuf};
+use clap::ValueEnum;
 use mysql::prelude::Queryable;
+use mysql::{PooledConn, TxOpts};
 use tokio::task;
-use futures::future;
-use std::io::Error;
 
+/// Destination table holding the import progress of unfinished files,
+/// used with `--resume`.
+const PROGRESS_TABLE: &str = "_migration_progress";
+/// Error of a statement that rolled back its whole transaction.
+const ER_LOCK_DEADLOCK: u16 = 1213;
+
+/// How imported statements are grouped into transactions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Transaction {
+    /// Commit every `--transaction-size` statements
+    Statements,
+    /// Commit once `--transaction-size` rows have been inserted
+    Rows,
+    /// Import each table in a single transaction
+    Table,
+}
+
+/// Imports the export file of a table, or of each of its `outputs`, and
+/// returns the number of statements that failed. Files are read and
+/// executed with blocking calls, so they run on the blocking thread pool.
 pub async fn import(
+    args: Arc<Args>,
     database: Arc<Database>,
     table: Arc<TableConfig>,
     export_path: Arc<PathBuf>,
-) {
+    encryption: Option<Arc<Encryption>>,
+    state: Arc<StateStore>,
+) -> i32 {
+    let files: Vec<(String, Option<String>)> = match &table.outputs {
+        Some(outputs) => outputs.iter().map(|output| (output.name.clone(), None)).collect(),
+        None => vec![(table.name.clone(), table.table_rename.as_ref().map(|_| table.file_stem()))],
+    };
+    task::spawn_blocking(move || {
+        files
+            .iter()
+            .map(|(name, rename)| import_file(&args, &database, name, rename, &export_path, &encryption, &state))
+            .sum()
+    })
+    .await
+    .unwrap_or_else(|err| panic!("{}: import failed: {}", table.name, err))
+}
+
+/// Imports one export file. Progress is kept under the source table's
+/// name, or the output's name for a split table.
+fn import_file(
+    args: &Args,
+    database: &Database,
+    name: &str,
+    rename: &Option<String>,
+    export_path: &Path,
+    encryption: &Option<Arc<Encryption>>,
+    state: &Arc<StateStore>,
+) -> i32 {
+    let mut conn = database.pool.get_conn().unwrap();
+    // Only a run started with `--resume` keeps its progress in the
+    // destination, where it can commit with the statements it counts.
+    let run: Option<String> = args.resume.then(|| {
+        create_progress_table(&mut conn);
+        state.import_run()
+    });
+    let run = run.as_deref();
+    let mut progress: ImportProgress = if args.resume {
+        state.import_progress(name).unwrap_or_default()
+    } else {
+        ImportProgress::default()
+    };
+    if progress.completed {
+        println!("Skipping import of {}: already imported", name);
+        return 0;
+    }
+    if let Some(lines) = run.and_then(|run| read_progress(&mut conn, run, name)) {
+        progress.lines = lines;
+    }
+    if progress.lines > 0 {
+        println!("Resuming import of {} after line {}", name, progress.lines);
+    }
     let success_counter = Arc::new(Mutex::new(0));
     let error_counter = Arc::new(Mutex::new(0));
     let query_errors = Arc::new(Mutex::new(String::new()));
-    let file_path: String = get_file_path(&export_path, &table);
-    let error_path: String = get_error_file_name(&export_path, &table);
-    let file: File = File::open(&file_path).expect("Unable to open file");
-    let reader: BufReader<File> = BufReader::new(file);
-    let mut tasks = vec![];
-    for line in reader.lines() {
-        let query = line.expect("Unable to read line");
-        let database_clone = Arc::clone(&database);
-        let success_counter_clone = Arc::clone(&success_counter);
-        let error_counter_clone = Arc::clone(&error_counter);
-        let query_errors_clone = Arc::clone(&query_errors);
-        let task = task::spawn(async move {
-            let mut conn = database_clone.pool.get_conn().unwrap();
-            match conn.query_drop(&query) {
-                Ok(_) => {
-                    let mut success_counter = success_counter_clone.lock().unwrap();
-                    *success_counter += 1;
-                }
-                Err(err) => {
-                    // eprintln!("Error executing query: {}\nError details: {}", query, err);
-                    let mut error_counter = error_counter_clone.lock().unwrap();
-                    *error_counter += 1;
-                    let mut query_errors = query_errors_clone.lock().unwrap();
-                    let error = err.to_string();
-                    query_errors.push_str(&query);
-                    query_errors.push('\n');
-                    query_errors.push_str(error.as_str());
-                    query_errors.push('\n');
-                }
+    let file_path: String = get_file_path(export_path, name, rename);
+    let error_path: String = get_error_file_name(export_path, name, rename);
+    let file: Box<dyn Read + Send> = encryption::open_file(Path::new(&file_path), encryption)
+        .expect("Unable to open file");
+    let reader: BufReader<Box<dyn Read + Send>> = BufReader::new(file);
+    let mut lines_done: u64 = progress.lines;
+    let lines = reader.lines().skip(progress.lines as usize).map(|line| line.expect("Unable to read line"));
+    if args.transaction == Some(Transaction::Table) {
+        if !execute_table_transaction(&mut conn, name, run, lines_done, lines, &success_counter, &error_counter, &query_errors) {
+            // Left incomplete so that `--resume` imports the table again.
+            let error_count = *error_counter.lock().unwrap();
+            write_errors(&error_path, encryption, &query_errors);
+            return error_count;
+        }
+    } else {
+        // A checkpoint follows every `--checkpoint-rows` statements without
+        // `--transaction`, otherwise every transaction. Statements run in
+        // autocommit unless they are grouped by `--transaction`, or their
+        // progress is recorded in the destination.
+        let mut batch: Vec<String> = Vec::new();
+        let mut size: u64 = 0;
+        let execute = |conn: &mut PooledConn, batch: &[String], lines: u64| match (args.transaction, run) {
+            (None, None) => execute_autocommit(conn, batch, &success_counter, &error_counter, &query_errors),
+            (transaction, run) => execute_transaction(
+                conn, name, run, batch, lines, transaction.is_some(), &success_counter, &error_counter, &query_errors,
+            ),
+        };
+        for line in lines {
+            size += if args.transaction == Some(Transaction::Rows) { count_rows(&line) } else { 1 };
+            batch.push(line);
+            if size >= args.transaction.map_or(args.checkpoint_rows, |_| args.transaction_size) {
+                lines_done += batch.len() as u64;
+                execute(&mut conn, &batch, lines_done);
+                batch.clear();
+                size = 0;
+                state.set_import_progress(name, ImportProgress {
+                    completed: false,
+                    lines: lines_done,
+                });
             }
-        });
-        tasks.push(task);
+        }
+        if !batch.is_empty() {
+            lines_done += batch.len() as u64;
+            execute(&mut conn, &batch, lines_done);
+        }
+    }
+    state.set_import_progress(name, ImportProgress {
+        completed: true,
+        ..Default::default()
+    });
+    if let Some(run) = run {
+        delete_progress(&mut conn, run, name);
     }
-    future::join_all(tasks).await;
     let success_count = *success_counter.lock().unwrap();
     let error_count = *error_counter.lock().unwrap();
     println!("Completed importing {} with {} success and {} errors", file_path, success_count, error_count);
     if error_count > 0 {
-        let error_file = get_error_file(&error_path)
-            .expect(&format!("Unable to create error file {}", error_path));
-        let mut writer = BufWriter::new(error_file);
-        let query_errors = query_errors.lock().unwrap();
-        match writer.write_all(query_errors.as_bytes()) {
-            Ok(_) => (),
-            Err(error) => eprintln!("Error writing to error file: {}\nError details: {}", error_path, error)
+        write_errors(&error_path, encryption, &query_errors);
+    }
+    error_count
+}
+
+/// Creates the destination table that counts the executed lines of each
+/// export file. It is written in the transaction that executes those lines,
+/// so a resumed import continues exactly after the last commit.
+fn create_progress_table(conn: &mut PooledConn) {
+    let query = format!(
+        "CREATE TABLE IF NOT EXISTS {} (run_id CHAR(32) NOT NULL, name VARCHAR(255) NOT NULL, \
+         line_count BIGINT UNSIGNED NOT NULL, PRIMARY KEY (run_id, name))",
+        PROGRESS_TABLE
+    );
+    conn.query_drop(query)
+        .unwrap_or_else(|err| panic!("Unable to create the import progress table {}: {}", PROGRESS_TABLE, err));
+}
+
+fn read_progress(conn: &mut PooledConn, run: &str, name: &str) -> Option<u64> {
+    let query = format!("SELECT line_count FROM {} WHERE run_id = ? AND name = ?", PROGRESS_TABLE);
+    conn.exec_first(query, (run, name))
+        .unwrap_or_else(|err| panic!("{}: unable to read the import progress: {}", name, err))
+}
+
+fn write_progress(conn: &mut impl Queryable, run: &str, name: &str, lines: u64) -> mysql::Result<()> {
+    let query = format!(
+        "INSERT INTO {} (run_id, name, line_count) VALUES (?, ?, ?) ON DUPLICATE KEY UPDATE line_count = ?",
+        PROGRESS_TABLE
+    );
+    conn.exec_drop(query, (run, name, lines, lines))
+}
+
+/// Removes the progress of a completed file, which the state file now
+/// records.
+fn delete_progress(conn: &mut PooledConn, run: &str, name: &str) {
+    let query = format!("DELETE FROM {} WHERE run_id = ? AND name = ?", PROGRESS_TABLE);
+    if let Err(err) = conn.exec_drop(query, (run, name)) {
+        eprintln!("{}: unable to remove the import progress: {}", name, err);
+    }
+}
+
+/// Drops the progress table once every file of a `--resume` run has been
+/// imported.
+pub fn drop_progress_table(database: &Database) {
+    let mut conn = database.pool.get_conn().unwrap();
+    if let Err(err) = conn.query_drop(format!("DROP TABLE IF EXISTS {}", PROGRESS_TABLE)) {
+        eprintln!("Unable to drop the import progress table {}: {}", PROGRESS_TABLE, err);
+    }
+}
+
+/// Whether an error rolled back the whole transaction on the server rather
+/// than just its statement: a deadlock, or a lost connection.
+fn ends_transaction(err: &mysql::Error) -> bool {
+    match err {
+        mysql::Error::MySqlError(error) => error.code == ER_LOCK_DEADLOCK,
+        _ => true,
+    }
+}
+
+fn write_errors(error_path: &str, encryption: &Option<Arc<Encryption>>, query_errors: &Arc<Mutex<String>>) {
+    let error_file = encryption::create_file(Path::new(error_path), encryption)
+        .unwrap_or_else(|_| panic!("Unable to create error file {}", error_path));
+    let mut writer = BufWriter::new(error_file);
+    let query_errors = query_errors.lock().unwrap();
+    let result = writer
+        .write_all(query_errors.as_bytes())
+        .and_then(|_| writer.into_inner().map_err(io::IntoInnerError::into_error))
+        .and_then(|mut file| file.finish());
+    match result {
+        Ok(_) => (),
+        Err(error) => eprintln!("Error writing to error file: {}\nError details: {}", error_path, error)
+    }
+}
+
+fn record_error(query_errors: &Arc<Mutex<String>>, query: &str, error: &str) {
+    let mut query_errors = query_errors.lock().unwrap();
+    query_errors.push_str(query);
+    query_errors.push('\n');
+    query_errors.push_str(error);
+    query_errors.push('\n');
+}
+
+/// Executes statements one at a time in autocommit, recording the ones
+/// that fail.
+fn execute_autocommit(
+    conn: &mut PooledConn,
+    queries: &[String],
+    success_counter: &Arc<Mutex<i32>>,
+    error_counter: &Arc<Mutex<i32>>,
+    query_errors: &Arc<Mutex<String>>,
+) {
+    for query in queries {
+        match conn.query_drop(query) {
+            Ok(_) => *success_counter.lock().unwrap() += 1,
+            Err(err) => {
+                *error_counter.lock().unwrap() += 1;
+                record_error(query_errors, query, &err.to_string());
+            }
         }
     }
 }
 
+/// Executes a group of statements in one transaction that also records
+/// `lines` as the file's progress of `run`, if any. With `atomic`, or when
+/// an error ends the transaction, the first error rolls back the group and
+/// every statement of it is recorded as failed; otherwise only the failing
+/// statements are.
+#[allow(clippy::too_many_arguments)]
+fn execute_transaction(
+    conn: &mut PooledConn,
+    name: &str,
+    run: Option<&str>,
+    queries: &[String],
+    lines: u64,
+    atomic: bool,
+    success_counter: &Arc<Mutex<i32>>,
+    error_counter: &Arc<Mutex<i32>>,
+    query_errors: &Arc<Mutex<String>>,
+) {
+    let mut errors: Vec<(usize, String)> = Vec::new();
+    let mut failed: Option<(usize, String)> = None;
+    match conn.start_transaction(TxOpts::default()) {
+        Ok(mut transaction) => {
+            for (index, query) in queries.iter().enumerate() {
+                if let Err(err) = transaction.query_drop(query) {
+                    if atomic || ends_transaction(&err) {
+                        failed = Some((index, err.to_string()));
+                        break;
+                    }
+                    errors.push((index, err.to_string()));
+                }
+            }
+            if let (None, Some(run)) = (&failed, run) {
+                if let Err(err) = write_progress(&mut transaction, run, name, lines) {
+                    failed = Some((queries.len(), format!("Unable to record the import progress: {}", err)));
+                }
+            }
+            let result = match failed {
+                None => transaction.commit(),
+                Some(_) => transaction.rollback(),
+            };
+            if let Err(err) = result {
+                failed.get_or_insert((queries.len(), format!("Unable to commit: {}", err)));
+            }
+        }
+        Err(err) => failed = Some((queries.len(), format!("Unable to start a transaction: {}", err))),
+    }
+    match failed {
+        None => {
+            *success_counter.lock().unwrap() += (queries.len() - errors.len()) as i32;
+            *error_counter.lock().unwrap() += errors.len() as i32;
+            for (index, error) in errors {
+                record_error(query_errors, &queries[index], &error);
+            }
+        }
+        Some((failed_index, error)) => {
+            eprintln!("{}: rolled back a transaction of {} statements after an error", name, queries.len());
+            *error_counter.lock().unwrap() += queries.len() as i32;
+            for (index, query) in queries.iter().enumerate() {
+                if let Some((_, own_error)) = errors.iter().find(|(failed, _)| *failed == index) {
+                    record_error(query_errors, query, own_error);
+                } else if index == failed_index || failed_index == queries.len() {
+                    record_error(query_errors, query, &error);
+                } else {
+                    record_error(query_errors, query, "Rolled back with the rest of its transaction");
+                }
+            }
+            // The statements are in the error file, so the group counts as
+            // done and a resumed import continues after it.
+            if let Some(run) = run {
+                if let Err(err) = write_progress(conn, run, name, lines) {
+                    eprintln!("{}: unable to record the import progress: {}", name, err);
+                }
+            }
+        }
+    }
+}
+
+/// Executes every remaining statement of a table in one transaction,
+/// stopping at the first error and rolling back so the destination is left
+/// untouched. The transaction records the file as fully executed when the
+/// run keeps its progress. Returns whether it was committed.
+#[allow(clippy::too_many_arguments)]
+fn execute_table_transaction(
+    conn: &mut PooledConn,
+    name: &str,
+    run: Option<&str>,
+    lines_done: u64,
+    queries: impl Iterator<Item = String>,
+    success_counter: &Arc<Mutex<i32>>,
+    error_counter: &Arc<Mutex<i32>>,
+    query_errors: &Arc<Mutex<String>>,
+) -> bool {
+    let mut transaction = match conn.start_transaction(TxOpts::default()) {
+        Ok(transaction) => transaction,
+        Err(err) => {
+            // Recorded like a group that could not start, statement by statement.
+            eprintln!("{}: unable to start a transaction, nothing was imported: {}", name, err);
+            let error = format!("Unable to start a transaction: {}", err);
+            for query in queries {
+                *error_counter.lock().unwrap() += 1;
+                record_error(query_errors, &query, &error);
+            }
+            return false;
+        }
+    };
+    let mut executed: i32 = 0;
+    for query in queries {
+        if let Err(err) = transaction.query_drop(&query) {
+            if let Err(err) = transaction.rollback() {
+                eprintln!("{}: unable to roll back: {}", name, err);
+            }
+            eprintln!("{}: rolled back the import after an error, nothing was imported", name);
+            *error_counter.lock().unwrap() += executed + 1;
+            record_error(query_errors, &query, &err.to_string());
+            return false;
+        }
+        executed += 1;
+    }
+    if let Some(run) = run {
+        if let Err(err) = write_progress(&mut transaction, run, name, lines_done + executed as u64) {
+            eprintln!("{}: unable to record the import progress, nothing was imported: {}", name, err);
+            *error_counter.lock().unwrap() += executed;
+            record_error(query_errors, &format!("INSERT INTO {}", PROGRESS_TABLE), &err.to_string());
+            return false;
+        }
+    }
+    match transaction.commit() {
+        Ok(_) => {
+            *success_counter.lock().unwrap() += executed;
+            true
+        }
+        Err(err) => {
+            eprintln!("{}: unable to commit, nothing was imported: {}", name, err);
+            *error_counter.lock().unwrap() += executed;
+            record_error(query_errors, "COMMIT", &err.to_string());
+            false
+        }
+    }
+}
+
+/// Number of rows an insert statement writes: the value lists after
+/// `VALUES`, skipping parentheses inside quoted values and function calls,
+/// up to an `ON DUPLICATE KEY UPDATE` clause.
+fn count_rows(statement: &str) -> u64 {
+    let values = match statement.find(" VALUES") {
+        Some(position) => &statement[position..],
+        None => return 1,
+    };
+    let mut rows: u64 = 0;
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (index, c) in values.char_indices() {
+        if let Some(open) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == open {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' => quote = Some(c),
+            '(' => {
+                if depth == 0 {
+                    rows += 1;
+                }
+                depth += 1;
+            }
+            ')' => depth -= 1,
+            ' ' if depth == 0 && values[index..].starts_with(" ON DUPLICATE KEY UPDATE") => break,
+            _ => {}
+        }
+    }
+    rows.max(1)
+}
+
 fn get_file_path(
-    export_path: &Arc<PathBuf>,
-    table: &Arc<TableConfig>
+    export_path: &Path,
+    name: &str,
+    rename: &Option<String>
 ) -> String {
     let path = export_path.display().to_string();
-    match &table.table_rename {
-        Some(rename) => format!("{}\\{}.sql", path, rename),
-        None => format!("{}\\{}.sql", path, table.name),
+    match rename {
+        Some(rename) => format!("{}/{}.sql", path, rename),
+        None => format!("{}/{}.sql", path, name),
     }
 }
 
-fn get_error_file_name(export_path: &PathBuf, table: &TableConfig) -> String {
+fn get_error_file_name(export_path: &Path, name: &str, rename: &Option<String>) -> String {
     let path = export_path.display().to_string();
-    match &table.table_rename {
-        Some(rename) => format!("{}\\err-{}.sql",path, rename),
-        None => format!("{}\\{}-error.sql", path, table.name),
+    match rename {
+        Some(rename) => format!("{}/err-{}.sql", path, rename),
+        None => format!("{}/{}-error.sql", path, name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_value_lists() {
+        assert_eq!(count_rows("INSERT INTO t VALUES (1, 'a'), (2, 'b'), (3, 'c');"), 3);
+        assert_eq!(count_rows("INSERT INTO t (id, name) VALUES (1, 'a');"), 1);
+    }
+
+    #[test]
+    fn stops_at_the_update_clause() {
+        let statement = "INSERT INTO t (`id`, `name`) VALUES (1, ' ON DUPLICATE KEY UPDATE ('), (2, 'b') \
+                         ON DUPLICATE KEY UPDATE `id` = VALUES(`id`), `name` = VALUES(`name`);";
+        assert_eq!(count_rows(statement), 2);
+    }
+
+    #[test]
+    fn skips_parentheses_in_values() {
+        assert_eq!(count_rows("INSERT INTO t VALUES (1, '(x), (y)'), (2, \"it's (here)\");"), 2);
+        assert_eq!(count_rows("INSERT INTO t VALUES (1, 'a\\'), ('), (2, 'b');"), 2);
+        assert_eq!(count_rows("INSERT INTO t VALUES (1, ST_GeomFromWKB(X'01', 4326)), (2, CONCAT('(', ')'));"), 2);
+    }
+
+    #[test]
+    fn counts_other_statements_once() {
+        assert_eq!(count_rows("UPDATE t SET a = 1"), 1);
+        assert_eq!(count_rows("INSERT INTO t SELECT * FROM u"), 1);
     }
 }
-fn get_error_file(error_path: &String) -> Result<File, Error> {
-    let error_path: &Path = Path::new(&error_path);
-    let error_file: Result<File, Error>;
-    error_file = match error_path.exists() {
-        true => File::open(&error_path),
-        false => File::create(&error_path),
-    };
-    return error_file;
-}
\ No newline at end of file
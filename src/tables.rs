@@ -1,20 +1,294 @@
 use std::fs;
 use std::collections::HashMap;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use serde_json::Value;
 
 use arguments::Args;
 
 use crate::arguments;
+use crate::connection::Discriminator;
+use crate::discovery::TablePattern;
+use crate::overrides;
+use crate::pseudonym::PseudonymFormat;
 
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverrideKind {
+    /// Set columns of rows that match the override's conditions
+    #[default]
+    Value,
+    /// Replace paths inside the JSON column `name` on every row
+    JsonPath,
+}
+
+/// How a column is compared with the configured value.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Operator {
+    #[default]
+    #[serde(rename = "=")]
+    Eq,
+    #[serde(rename = "!=")]
+    Ne,
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "<=")]
+    Le,
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = ">=")]
+    Ge,
+    #[serde(rename = "in")]
+    In,
+    #[serde(rename = "not_in")]
+    NotIn,
+    /// Inclusive range given as `values: [low, high]`
+    #[serde(rename = "between")]
+    Between,
+    /// SQL pattern with `%` and `_`, case-insensitive
+    #[serde(rename = "like")]
+    Like,
+    #[serde(rename = "regex")]
+    Regex,
+}
+
+/// Keeps an explicit `null` apart from a missing value.
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+/// A test of one column of the row.
 #[derive(Debug, Deserialize, Clone)]
-pub struct Override {
+pub struct Condition {
     pub name: String,
-    pub value: String,
-    pub set: HashMap<String, String>,
+    #[serde(default)]
+    pub op: Operator,
+    #[serde(default, deserialize_with = "present")]
+    pub value: Option<Value>,
+    pub values: Option<Vec<Value>>,
+}
+
+impl Condition {
+    pub fn values(&self) -> Vec<Value> {
+        match (&self.value, &self.values) {
+            (Some(value), _) => vec![value.clone()],
+            (None, Some(values)) => values.clone(),
+            (None, None) => Vec::new(),
+        }
+    }
+}
+
+/// A `value` override matches when its own condition, if `name` is given,
+/// and every condition in `all` hold, and at least one in `any` does.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Override {
+    #[serde(default)]
+    pub kind: OverrideKind,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub op: Operator,
+    #[serde(default, deserialize_with = "present")]
+    pub value: Option<Value>,
+    pub values: Option<Vec<Value>>,
+    pub all: Option<Vec<Condition>>,
+    pub any: Option<Vec<Condition>>,
+    pub set: HashMap<String, Value>,
+}
+
+impl Override {
+    /// The conditions that must all hold.
+    pub fn conditions(&self) -> Vec<Condition> {
+        let own = self.name.as_ref().map(|name| Condition {
+            name: name.clone(),
+            op: self.op,
+            value: self.value.clone(),
+            values: self.values.clone(),
+        });
+        own.into_iter().chain(self.all.iter().flatten().cloned()).collect()
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ColumnTimezone {
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct IncrementalConfig {
+    /// Monotonically increasing column such as `updated_at` or `id`
+    pub column: String,
+}
+
+/// How the values of one column are masked, chosen by `strategy`.
+#[derive(Debug, Clone)]
+pub enum MaskRule {
+    /// A value generated from a hash salted with the mask secret
+    Generate(GeneratedMask),
+    /// Keyed pseudonym that is the same wherever the value appears
+    Pseudonymize(PseudonymMask),
+}
+
+impl<'de> Deserialize<'de> for MaskRule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let rule = if value.get("strategy").and_then(Value::as_str) == Some("pseudonymize") {
+            PseudonymMask::deserialize(value).map(MaskRule::Pseudonymize)
+        } else {
+            GeneratedMask::deserialize(value).map(MaskRule::Generate)
+        };
+        rule.map_err(serde::de::Error::custom)
+    }
+}
+
+/// Mask strategies that replace a value with one derived from it.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum GeneratedMask {
+    FakeName,
+    FakeEmail,
+    FakePhone,
+    FakeAddress,
+    /// Replaces every value with `with`, `REDACTED` by default
+    Redact { with: Option<String> },
+    /// Keeps the first `keep_start` and last `keep_end` characters
+    Partial {
+        #[serde(default)]
+        keep_start: usize,
+        #[serde(default)]
+        keep_end: usize,
+        mask_char: Option<char>,
+    },
+    Nullify,
+    Hash,
+    /// Exchanges values between the rows of the table
+    Shuffle,
+    /// Moves dates by up to `days` days in either direction
+    DateJitter { days: i64 },
+}
+
+impl GeneratedMask {
+    /// Whether the output depends on the mask secret.
+    pub fn is_keyed(&self) -> bool {
+        !matches!(self, GeneratedMask::Redact { .. } | GeneratedMask::Partial { .. } | GeneratedMask::Nullify)
+    }
+}
+
+/// Options of the `pseudonymize` strategy.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PseudonymMask {
+    #[serde(default)]
+    pub format: PseudonymFormat,
+    #[serde(default)]
+    pub keep_domain: bool,
+    /// Numeric type whose range `numeric` pseudonyms take, `bigint` by
+    /// default
+    pub domain: Option<String>,
+}
+
+/// How new IDs are chosen for a remapped table.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum RemapStrategy {
+    /// Adds `by` to every ID, the destination's highest ID by default
+    Offset { by: Option<i64> },
+    /// Numbers IDs in order from `start`, after the destination's highest
+    /// ID by default
+    Sequence { start: Option<i64> },
 }
 
 #[derive(Debug, Deserialize, Clone)]
+pub struct RemapConfig {
+    /// Integer column to remap, the single-column primary key by default
+    pub column: Option<String>,
+    #[serde(flatten)]
+    pub strategy: RemapStrategy,
+}
+
+/// Destination type a `cast` rule converts a column to.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "to", rename_all = "snake_case")]
+pub enum CastType {
+    Integer,
+    Decimal {
+        precision: Option<u32>,
+        scale: Option<u32>,
+    },
+    /// One of `values`, matched case-insensitively
+    Enum { values: Vec<String> },
+    /// From `epoch`, `epoch_ms` or a chrono format, `YYYY-MM-DD HH:MM:SS`
+    /// by default
+    Datetime { from: Option<String> },
+    Date { from: Option<String> },
+    Text { max_length: Option<usize> },
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CastRule {
+    #[serde(flatten)]
+    pub to: CastType,
+    /// Source values replaced before converting
+    pub map: Option<HashMap<String, Value>>,
+    /// Characters removed before converting
+    pub strip: Option<String>,
+    /// Rejects the row instead of writing NULL when a value cannot be
+    /// converted
+    #[serde(default)]
+    pub strict: bool,
+}
+
+/// Which row of a group of duplicates `dedupe` keeps.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupeKeep {
+    /// Lowest primary key
+    #[default]
+    First,
+    /// Highest primary key
+    Last,
+    /// Highest value of `by`
+    Max,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DedupeConfig {
+    /// Exported columns whose values must be unique together
+    pub key: Vec<String>,
+    #[serde(default)]
+    pub keep: DedupeKeep,
+    pub by: Option<String>,
+    /// Compares text case-insensitively, as most collations do
+    #[serde(default)]
+    pub ignore_case: bool,
+}
+
+/// How the value of a column added to every row is produced.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AddColumn {
+    /// The same value in every row
+    Constant { value: Value },
+    /// When the run started
+    Now,
+    /// A random UUID for each row
+    Uuid,
+    /// A `transform` expression over the row as it is written
+    Expression { expression: String },
+}
+
+/// One of the destination tables a source table is split into.
+#[derive(Debug, Deserialize, Clone)]
+pub struct TableOutput {
+    /// Destination table
+    pub name: String,
+    /// Exported columns written to this table, all of them by default
+    pub columns: Option<Vec<String>>,
+    pub column_rename: Option<HashMap<String, String>>,
+    /// Conditions a row must all meet to be written to this table
+    #[serde(rename = "where")]
+    pub filter: Option<Vec<Condition>>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct TableConfig {
     pub name: String,
     #[serde(default)]
@@ -23,14 +297,66 @@ pub struct TableConfig {
     pub condition: Option<String>,
     pub overrides: Option<Vec<Override>>,
     pub column_rename: Option<HashMap<String, String>>,
+    pub timezones: Option<HashMap<String, ColumnTimezone>>,
+    pub repair_mojibake: Option<bool>,
+    /// Skips rows with invalid JSON instead of writing it as NULL
+    pub reject_invalid_json: Option<bool>,
+    pub incremental: Option<IncrementalConfig>,
+    /// Starting point of `--subset`, selected by `condition`
+    pub subset_root: Option<bool>,
+    pub mask: Option<HashMap<String, MaskRule>>,
+    /// Expressions computing a column from the fetched row
+    pub transform: Option<HashMap<String, String>>,
+    /// Rhai script defining `transform(row)`
+    pub script: Option<String>,
+    pub remap: Option<RemapConfig>,
+    /// Columns holding IDs of remapped tables, for references without a
+    /// foreign key: column to table name
+    pub references: Option<HashMap<String, String>>,
+    /// Conversions of columns whose type differs in the destination
+    pub cast: Option<HashMap<String, CastRule>>,
+    /// Keeps one row of each group with the same key
+    pub dedupe: Option<DedupeConfig>,
+    /// Columns that are not in the source, added to every row
+    pub add_columns: Option<HashMap<String, AddColumn>>,
+    /// Destination tables the rows are written to, instead of one table
+    pub outputs: Option<Vec<TableOutput>>,
+    /// Source tables matching these patterns are exported into this table
+    pub union: Option<Vec<String>>,
+    /// Column recording which `union` table each row comes from
+    pub origin_column: Option<String>,
+    /// Constant columns identifying where the rows come from: the source,
+    /// and the table of a `union`
+    #[serde(skip)]
+    pub discriminators: Vec<Discriminator>,
+    /// Whether the table was expanded from a `union`
+    #[serde(skip)]
+    pub union_member: bool,
 }
 
-pub fn get_config(args: &Args) -> Vec<TableConfig> {
-    let config: &str = args.table_config
-        .as_deref()
-        .unwrap_or("table.json");
-    let table_json: String = fs::read_to_string(config)
-        .expect("Failed to read table.json");
+impl TableConfig {
+    /// Name of the table's export file, without the extension: the
+    /// destination table, followed by the source table for a `union`.
+    pub fn file_stem(&self) -> String {
+        match (&self.table_rename, self.union_member) {
+            (Some(rename), true) => format!("{}.{}", rename, self.name),
+            (Some(rename), false) => rename.clone(),
+            (None, _) => self.name.clone(),
+        }
+    }
+}
+
+/// Reads the table configuration of a source: its own file when it has
+/// one, `--table-config` otherwise.
+pub fn get_config(args: &Args, source_config: Option<&str>) -> Vec<TableConfig> {
+    let path: Option<&str> = source_config.or(args.table_config.as_deref());
+    let config: &str = path.unwrap_or("table.json");
+    let table_json: String = match fs::read_to_string(config) {
+        Ok(table_json) => table_json,
+        // Discovery fills in the tables when there is no table configuration.
+        Err(_) if args.discover && path.is_none() => return Vec::new(),
+        Err(err) => panic!("Failed to read {}: {}", config, err),
+    };
 
     let table_configs: Vec<TableConfig> = process_table_configuration(table_json);
 
@@ -45,16 +371,103 @@ fn process_table_configuration(table_json: String) -> Vec<TableConfig> {
             let name = table.as_str().unwrap().to_string(); 
             TableConfig {
                 name,
-                columns: None,
-                table_rename: None,
-                condition: None,
-                overrides: None,
-                column_rename: None,
+                ..Default::default()
             }
         } else {
             serde_json::from_value(table.clone()).expect("Failed to parse table")
         };
+        validate_overrides(&table_config);
+        validate_outputs(&table_config);
+        validate_union(&table_config);
+        validate_dedupe(&table_config);
         table_configs.push(table_config);
     }
     table_configs
 }
+
+fn validate_outputs(table: &TableConfig) {
+    let outputs = match &table.outputs {
+        Some(outputs) => outputs,
+        None => return,
+    };
+    if outputs.is_empty() {
+        panic!("{}: outputs must list at least one table", table.name);
+    }
+    if table.table_rename.is_some() || table.column_rename.is_some() {
+        panic!("{}: table_rename and column_rename cannot be combined with outputs", table.name);
+    }
+    for (index, output) in outputs.iter().enumerate() {
+        if outputs[..index].iter().any(|other| other.name == output.name) {
+            panic!("{}: output {} is listed twice", table.name, output.name);
+        }
+        for condition in output.filter.iter().flatten() {
+            if let Err(err) = overrides::compile_pattern(condition) {
+                panic!("{}: {}", table.name, err);
+            }
+        }
+    }
+}
+
+fn validate_union(table: &TableConfig) {
+    let patterns = match &table.union {
+        Some(patterns) => patterns,
+        None if table.origin_column.is_some() => {
+            panic!("{}: origin_column needs a union", table.name);
+        }
+        None => return,
+    };
+    if patterns.is_empty() {
+        panic!("{}: union must list at least one table", table.name);
+    }
+    if table.table_rename.is_some() || table.outputs.is_some() || table.remap.is_some() {
+        panic!("{}: union cannot be combined with table_rename, outputs or remap", table.name);
+    }
+    for pattern in patterns {
+        if let Err(err) = TablePattern::parse(pattern) {
+            panic!("{}: {}", table.name, err);
+        }
+    }
+}
+
+fn validate_dedupe(table: &TableConfig) {
+    let dedupe = match &table.dedupe {
+        Some(dedupe) => dedupe,
+        None => return,
+    };
+    if dedupe.key.is_empty() {
+        panic!("{}: dedupe needs at least one key column", table.name);
+    }
+    match (dedupe.keep, &dedupe.by) {
+        (DedupeKeep::Max, None) => panic!("{}: dedupe keep max needs a `by` column", table.name),
+        (DedupeKeep::First | DedupeKeep::Last, Some(_)) => {
+            panic!("{}: dedupe `by` is only used with keep max", table.name)
+        }
+        _ => {}
+    }
+}
+
+fn validate_overrides(table: &TableConfig) {
+    for o in table.overrides.iter().flatten() {
+        match o.kind {
+            OverrideKind::JsonPath if o.name.is_none() => {
+                panic!("{}: json_path override is missing the column name", table.name);
+            }
+            OverrideKind::JsonPath => {}
+            OverrideKind::Value => {
+                if o.name.is_none() && o.all.is_none() && o.any.is_none() {
+                    panic!("{}: override needs a name, all or any condition", table.name);
+                }
+                for condition in o.conditions().iter().chain(o.any.iter().flatten()) {
+                    if let Err(err) = overrides::compile_pattern(condition) {
+                        panic!("{}: {}", table.name, err);
+                    }
+                }
+                for value in o.set.values().filter_map(Value::as_str) {
+                    if let Err(err) = overrides::parse_template(value) {
+                        panic!("{}: {}", table.name, err);
+                    }
+                }
+            }
+        }
+    }
+}
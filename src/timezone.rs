@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use chrono::{Datelike, Duration, LocalResult, NaiveDate, NaiveDateTime, Offset, TimeZone, Timelike};
+use chrono_tz::Tz;
+use clap::ValueEnum;
+use mysql::Value as MySqlValue;
+use arguments::Args;
+use crate::arguments;
+use crate::schema::ColumnInfo;
+use crate::tables::TableConfig;
+
+/// How to resolve a local time that occurs twice because of a DST fall-back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Ambiguity {
+    /// Use the earlier of the two instants
+    Earliest,
+    /// Use the later of the two instants
+    Latest,
+    /// Export the value as NULL
+    Null,
+}
+
+/// Zone pair used to convert one column.
+#[derive(Debug, Clone, Copy)]
+pub struct ZoneConversion {
+    from: Tz,
+    to: Tz,
+}
+
+/// Counts of local times that needed a DST decision, per column.
+#[derive(Debug, Default)]
+pub struct TimezoneReport {
+    pub ambiguous: HashMap<String, usize>,
+    pub nonexistent: HashMap<String, usize>,
+}
+
+impl TimezoneReport {
+    pub fn print(&self, table: &str, policy: Ambiguity) {
+        for (column, count) in &self.ambiguous {
+            println!(
+                "{}.{}: resolved {} ambiguous local times using {:?}",
+                table, column, count, policy
+            );
+        }
+        for (column, count) in &self.nonexistent {
+            println!(
+                "{}.{}: shifted {} nonexistent local times forward past the DST gap",
+                table, column, count
+            );
+        }
+    }
+}
+
+pub fn parse_zone(name: &str) -> Tz {
+    name.parse::<Tz>()
+        .unwrap_or_else(|_| panic!("Unknown timezone {}", name))
+}
+
+/// Checks a timezone option when the arguments are parsed.
+pub fn parse_zone_name(name: &str) -> Result<String, String> {
+    match name.parse::<Tz>() {
+        Ok(_) => Ok(name.to_string()),
+        Err(_) => Err(format!("unknown IANA timezone {}", name)),
+    }
+}
+
+/// Whether any run or table setting asks for timezone conversion.
+pub fn is_enabled(args: &Args, table_config: &[TableConfig]) -> bool {
+    args.source_timezone.is_some()
+        || args.destination_timezone.is_some()
+        || table_config.iter().any(|table| table.timezones.is_some())
+}
+
+/// Session statements that pin TIMESTAMP columns to UTC on both ends, so
+/// they are exported and imported as absolute instants.
+pub fn session_init(args: &Args, table_config: &[TableConfig]) -> Vec<String> {
+    if is_enabled(args, table_config) {
+        vec!["SET time_zone = '+00:00'".to_string()]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Resolves the conversion to apply to each exported column, by position.
+///
+/// DATETIME columns use the run-level `--source-timezone` and
+/// `--destination-timezone` unless the table's `timezones` map overrides
+/// them. DATE and TIMESTAMP columns are only converted when named
+/// explicitly in `timezones`.
+pub fn get_conversions(
+    args: &Args,
+    table: &TableConfig,
+    columns: &[String],
+    column_info: &HashMap<String, ColumnInfo>,
+) -> Vec<Option<ZoneConversion>> {
+    let run_from = args.source_timezone.as_deref();
+    let run_to = args.destination_timezone.as_deref();
+    columns.iter().map(|column| {
+        let explicit = table.timezones
+            .as_ref()
+            .and_then(|timezones| timezones.get(column));
+        let (from, to) = match explicit {
+            Some(tz) => (
+                tz.from.as_deref().or(run_from),
+                tz.to.as_deref().or(run_to),
+            ),
+            None => match column_info.get(column) {
+                Some(info) if info.data_type == "datetime" => (run_from, run_to),
+                _ => (None, None),
+            },
+        };
+        if from.is_none() && to.is_none() {
+            return None;
+        }
+        let from = parse_zone(from.unwrap_or("UTC"));
+        let to = parse_zone(to.unwrap_or("UTC"));
+        if from == to {
+            None
+        } else {
+            Some(ZoneConversion { from, to })
+        }
+    }).collect()
+}
+
+/// Converts every `MySqlValue::Date` in the row that has a conversion.
+pub fn convert_row(
+    values: &mut [MySqlValue],
+    columns: &[String],
+    conversions: &[Option<ZoneConversion>],
+    policy: Ambiguity,
+    report: &mut TimezoneReport,
+) {
+    for (pos, conversion) in conversions.iter().enumerate() {
+        if let Some(conversion) = conversion {
+            let value = std::mem::replace(&mut values[pos], MySqlValue::NULL);
+            values[pos] = convert_value(value, &columns[pos], conversion, policy, report);
+        }
+    }
+}
+
+fn convert_value(
+    value: MySqlValue,
+    column: &str,
+    conversion: &ZoneConversion,
+    policy: Ambiguity,
+    report: &mut TimezoneReport,
+) -> MySqlValue {
+    let (year, month, day, hour, minute, second, micro) = match value {
+        MySqlValue::Date(0, 0, 0, 0, 0, 0, 0) => return value,
+        MySqlValue::Date(year, month, day, hour, minute, second, micro) => {
+            (year, month, day, hour, minute, second, micro)
+        }
+        _ => return value,
+    };
+    let naive: NaiveDateTime = match NaiveDate::from_ymd_opt(year as i32, month as u32, day as u32)
+        .and_then(|date| date.and_hms_micro_opt(hour as u32, minute as u32, second as u32, micro))
+    {
+        Some(naive) => naive,
+        None => return value,
+    };
+    let local = match conversion.from.from_local_datetime(&naive) {
+        LocalResult::Single(local) => local,
+        LocalResult::Ambiguous(earliest, latest) => {
+            *report.ambiguous.entry(column.to_string()).or_insert(0) += 1;
+            match policy {
+                Ambiguity::Earliest => earliest,
+                Ambiguity::Latest => latest,
+                Ambiguity::Null => return MySqlValue::NULL,
+            }
+        }
+        LocalResult::None => {
+            // Shift the wall time forward by the gap's length, which gives
+            // the instant of the wall time read with the offset in effect
+            // before the gap.
+            *report.nonexistent.entry(column.to_string()).or_insert(0) += 1;
+            let offset = |at: NaiveDateTime| {
+                conversion.from.offset_from_utc_datetime(&at).fix().local_minus_utc() as i64
+            };
+            let gap = Duration::seconds(offset(naive + Duration::days(1)) - offset(naive - Duration::days(1)));
+            match conversion.from.from_local_datetime(&(naive + gap)).earliest() {
+                Some(shifted) => shifted,
+                None => return MySqlValue::NULL,
+            }
+        }
+    };
+    let converted = local.with_timezone(&conversion.to).naive_local();
+    MySqlValue::Date(
+        converted.year() as u16,
+        converted.month() as u8,
+        converted.day() as u8,
+        converted.hour() as u8,
+        converted.minute() as u8,
+        converted.second() as u8,
+        converted.nanosecond() / 1000,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(from: &str, value: MySqlValue) -> MySqlValue {
+        let conversion = ZoneConversion { from: parse_zone(from), to: parse_zone("UTC") };
+        convert_value(value, "at", &conversion, Ambiguity::Earliest, &mut TimezoneReport::default())
+    }
+
+    #[test]
+    fn shifts_past_gaps_of_any_length() {
+        // New York skips an hour, Lord Howe half an hour.
+        assert_eq!(
+            convert("America/New_York", MySqlValue::Date(2024, 3, 10, 2, 30, 0, 0)),
+            MySqlValue::Date(2024, 3, 10, 7, 30, 0, 0)
+        );
+        assert_eq!(
+            convert("Australia/Lord_Howe", MySqlValue::Date(2024, 10, 6, 2, 15, 0, 0)),
+            MySqlValue::Date(2024, 10, 5, 15, 45, 0, 0)
+        );
+    }
+
+    #[test]
+    fn resolves_ambiguous_times() {
+        assert_eq!(
+            convert("America/New_York", MySqlValue::Date(2024, 11, 3, 1, 30, 0, 0)),
+            MySqlValue::Date(2024, 11, 3, 5, 30, 0, 0)
+        );
+    }
+}
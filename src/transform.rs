@@ -0,0 +1,588 @@
+use std::collections::HashMap;
+use chrono::{NaiveDate, NaiveDateTime};
+use crate::cast;
+use crate::numeric;
+
+/// A parsed `transform` expression.
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Null,
+    Number(String),
+    Literal(String),
+    Column(usize),
+    Negate(Box<Expr>),
+    Binary(Box<Expr>, char, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+/// A transform of one exported column.
+#[derive(Debug, Clone)]
+pub struct ColumnTransform {
+    pub position: usize,
+    pub expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(String),
+    Text(String),
+    Symbol(char),
+}
+
+const FUNCTIONS: &[(&str, usize, usize)] = &[
+    ("CONCAT", 1, usize::MAX),
+    ("CONCAT_WS", 2, usize::MAX),
+    ("LOWER", 1, 1),
+    ("UPPER", 1, 1),
+    ("TRIM", 1, 1),
+    ("LENGTH", 1, 1),
+    ("COALESCE", 1, usize::MAX),
+    ("IFNULL", 2, 2),
+    ("SUBSTRING", 2, 3),
+    ("SUBSTR", 2, 3),
+    ("LEFT", 2, 2),
+    ("RIGHT", 2, 2),
+    ("REPLACE", 3, 3),
+    ("ROUND", 1, 2),
+    ("DATE_FORMAT", 2, 2),
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek() {
+                if !c.is_ascii_digit() && c != '.' {
+                    break;
+                }
+                number.push(c);
+                chars.next();
+            }
+            if number.parse::<f64>().is_err() {
+                return Err(format!("invalid number {}", number));
+            }
+            tokens.push(Token::Number(number));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut ident = String::new();
+            while let Some(&c) = chars.peek() {
+                if !c.is_alphanumeric() && c != '_' {
+                    break;
+                }
+                ident.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Ident(ident));
+        } else if c == '`' {
+            chars.next();
+            let ident: String = chars.by_ref().take_while(|c| *c != '`').collect();
+            tokens.push(Token::Ident(ident));
+        } else if c == '\'' || c == '"' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some('\\') => match chars.next() {
+                        Some('n') => text.push('\n'),
+                        Some('t') => text.push('\t'),
+                        Some(escaped) => text.push(escaped),
+                        None => return Err("unterminated string".to_string()),
+                    },
+                    // A doubled quote stands for the quote itself.
+                    Some(q) if q == c && chars.peek() == Some(&c) => {
+                        chars.next();
+                        text.push(c);
+                    }
+                    Some(q) if q == c => break,
+                    Some(other) => text.push(other),
+                    None => return Err("unterminated string".to_string()),
+                }
+            }
+            tokens.push(Token::Text(text));
+        } else if "+-*/%(),".contains(c) {
+            tokens.push(Token::Symbol(c));
+            chars.next();
+        } else {
+            return Err(format!("unexpected character {}", c));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    columns: &'a [String],
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Symbol(c)) if c == symbol => Ok(()),
+            _ => Err(format!("expected {}", symbol)),
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expr, String> {
+        let mut left = self.term()?;
+        while let Some(Token::Symbol(op @ ('+' | '-'))) = self.peek().cloned() {
+            self.next();
+            left = Expr::Binary(Box::new(left), op, Box::new(self.term()?));
+        }
+        Ok(left)
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut left = self.factor()?;
+        while let Some(Token::Symbol(op @ ('*' | '/' | '%'))) = self.peek().cloned() {
+            self.next();
+            left = Expr::Binary(Box::new(left), op, Box::new(self.factor()?));
+        }
+        Ok(left)
+    }
+
+    fn factor(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Symbol('-')) => Ok(Expr::Negate(Box::new(self.factor()?))),
+            Some(Token::Symbol('(')) => {
+                let expr = self.expression()?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Some(Token::Number(number)) => Ok(Expr::Number(number)),
+            Some(Token::Text(text)) => Ok(Expr::Literal(text)),
+            Some(Token::Ident(ident)) if self.peek() == Some(&Token::Symbol('(')) => {
+                self.next();
+                self.call(ident)
+            }
+            Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case("NULL") => Ok(Expr::Null),
+            Some(Token::Ident(ident)) => match self.columns.iter().position(|col| col == &ident) {
+                Some(position) => Ok(Expr::Column(position)),
+                None => Err(format!("column {} is not exported", ident)),
+            },
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+
+    fn call(&mut self, name: String) -> Result<Expr, String> {
+        let name = name.to_uppercase();
+        let (_, min, max) = FUNCTIONS
+            .iter()
+            .find(|(function, _, _)| *function == name)
+            .ok_or_else(|| format!("unknown function {}", name))?;
+        let mut args = Vec::new();
+        if self.peek() != Some(&Token::Symbol(')')) {
+            loop {
+                args.push(self.expression()?);
+                match self.next() {
+                    Some(Token::Symbol(',')) => continue,
+                    Some(Token::Symbol(')')) => break,
+                    _ => return Err(format!("expected , or ) in {}", name)),
+                }
+            }
+        } else {
+            self.next();
+        }
+        if args.len() < *min || args.len() > *max {
+            return Err(format!("wrong number of arguments to {}", name));
+        }
+        Ok(Expr::Call(name, args))
+    }
+}
+
+/// Parses an expression, resolving column names to their positions.
+pub fn parse(source: &str, columns: &[String]) -> Result<Expr, String> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+        columns,
+    };
+    let expr = parser.expression()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => Err(format!("unexpected {:?}", token)),
+    }
+}
+
+/// Parses the `transform` map of a table once, before rows are read.
+pub fn get_transforms(
+    table: &str,
+    columns: &[String],
+    transform: &Option<HashMap<String, String>>,
+) -> Vec<ColumnTransform> {
+    let mut transforms: Vec<ColumnTransform> = Vec::new();
+    for (column, source) in transform.iter().flatten() {
+        let position = match columns.iter().position(|col| col == column) {
+            Some(position) => position,
+            None => {
+                eprintln!("{}: transformed column {} is not exported", table, column);
+                continue;
+            }
+        };
+        let expr = parse(source, columns)
+            .unwrap_or_else(|err| panic!("{}.{}: {}", table, column, err));
+        transforms.push(ColumnTransform { position, expr });
+    }
+    transforms.sort_by_key(|transform| transform.position);
+    transforms
+}
+
+/// Evaluates every transform against the fetched values, then stores the
+/// results, so transforms never see each other's output.
+pub fn apply(values: &mut [String], transforms: &[ColumnTransform]) {
+    let results: Vec<Option<String>> = transforms
+        .iter()
+        .map(|transform| evaluate(&transform.expr, values))
+        .collect();
+    for (transform, result) in transforms.iter().zip(results) {
+        values[transform.position] = result.unwrap_or_else(|| "NULL".to_string());
+    }
+}
+
+/// Evaluates an expression with SQL semantics: `None` is NULL, and NULL
+/// operands make the result NULL except in `COALESCE` and `IFNULL`.
+pub fn evaluate(expr: &Expr, row: &[String]) -> Option<String> {
+    match expr {
+        Expr::Null => None,
+        Expr::Number(value) | Expr::Literal(value) => Some(value.clone()),
+        Expr::Column(position) if row[*position] == "NULL" => None,
+        Expr::Column(position) => Some(row[*position].clone()),
+        Expr::Negate(inner) => arithmetic("0", '-', &evaluate(inner, row)?),
+        Expr::Binary(left, op, right) => {
+            let left = evaluate(left, row)?;
+            let right = evaluate(right, row)?;
+            arithmetic(&left, *op, &right)
+        }
+        Expr::Call(name, args) => call(name, args, row),
+    }
+}
+
+fn to_number(value: &str) -> f64 {
+    // Like MySQL, text that does not start with a number counts as 0.
+    let end = value
+        .trim_start()
+        .char_indices()
+        .take_while(|(index, c)| c.is_ascii_digit() || *c == '.' || (*index == 0 && (*c == '-' || *c == '+')))
+        .count();
+    value.trim_start()[..end].parse().unwrap_or(0.0)
+}
+
+/// Digits a quotient has beyond the dividend's scale, MySQL's default
+/// `div_precision_increment`.
+const DIV_PRECISION_INCREMENT: u32 = 4;
+
+/// An exact decimal number, in units of its `scale`th decimal place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Decimal {
+    units: i128,
+    scale: u32,
+}
+
+impl Decimal {
+    /// Reads a plain decimal number such as `-12.50`, or returns None for
+    /// anything else, such as text or a float in exponent form. Numbers of
+    /// more than 38 digits do not fit and stop the export rather than lose
+    /// digits.
+    fn parse(value: &str) -> Option<Decimal> {
+        let value = value.trim();
+        let (negative, digits) = match value.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, value.strip_prefix('+').unwrap_or(value)),
+        };
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if (whole.is_empty() && fraction.is_empty()) || !whole.bytes().chain(fraction.bytes()).all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let all_digits = format!("{}{}", whole, fraction);
+        let units: i128 = all_digits
+            .trim_start_matches('0')
+            .parse()
+            .or_else(|_| if all_digits.bytes().all(|b| b == b'0') { Ok(0) } else { Err(()) })
+            .unwrap_or_else(|_| panic!("{} has too many digits for exact arithmetic", value));
+        Some(Decimal { units: if negative { -units } else { units }, scale: fraction.len() as u32 })
+    }
+
+    fn rescale(self, scale: u32) -> Option<i128> {
+        self.units.checked_mul(10i128.checked_pow(scale.checked_sub(self.scale)?)?)
+    }
+
+    /// Applies an operator with MySQL's DECIMAL scales: the larger scale for
+    /// `+`, `-` and `%`, their sum for `*`, and the dividend's plus four for
+    /// `/`, rounded half away from zero. Returns `Some(None)` for NULL, on
+    /// division by zero, and None on overflow.
+    fn apply(self, op: char, right: Decimal) -> Option<Option<Decimal>> {
+        let scale = self.scale.max(right.scale);
+        let result = match op {
+            '+' => Decimal { units: self.rescale(scale)?.checked_add(right.rescale(scale)?)?, scale },
+            '-' => Decimal { units: self.rescale(scale)?.checked_sub(right.rescale(scale)?)?, scale },
+            '*' => Decimal { units: self.units.checked_mul(right.units)?, scale: self.scale + right.scale },
+            '%' | '/' if right.units == 0 => return Some(None),
+            // Only i128::MIN % -1 overflows, and its remainder is 0.
+            '%' => Decimal { units: self.rescale(scale)?.checked_rem(right.rescale(scale)?).unwrap_or(0), scale },
+            _ => {
+                let scale = self.scale + DIV_PRECISION_INCREMENT;
+                let dividend = self.units.checked_mul(10i128.checked_pow(DIV_PRECISION_INCREMENT + right.scale)?)?;
+                let (quotient, remainder) = (dividend / right.units, dividend % right.units);
+                let away = if (dividend < 0) == (right.units < 0) { 1 } else { -1 };
+                let round_up = remainder.unsigned_abs() >= right.units.unsigned_abs() - remainder.unsigned_abs();
+                Decimal { units: if round_up { quotient.checked_add(away)? } else { quotient }, scale }
+            }
+        };
+        Some(Some(result))
+    }
+}
+
+impl std::fmt::Display for Decimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let digits = format!("{:0>width$}", self.units.unsigned_abs(), width = self.scale as usize + 1);
+        let (whole, fraction) = digits.split_at(digits.len() - self.scale as usize);
+        let sign = if self.units < 0 { "-" } else { "" };
+        match fraction {
+            "" => write!(f, "{}{}", sign, whole),
+            _ => write!(f, "{}{}.{}", sign, whole, fraction),
+        }
+    }
+}
+
+/// Exact for decimal numbers, as DECIMAL columns are exported, and with
+/// floats otherwise.
+fn arithmetic(left: &str, op: char, right: &str) -> Option<String> {
+    if let (Some(left), Some(right)) = (Decimal::parse(left), Decimal::parse(right)) {
+        return match left.apply(op, right) {
+            Some(result) => result.map(|result| result.to_string()),
+            None => panic!("{} {} {} is too large for exact arithmetic", left, op, right),
+        };
+    }
+    let (left, right) = (to_number(left), to_number(right));
+    let result = match op {
+        '+' => left + right,
+        '-' => left - right,
+        '*' => left * right,
+        // Division by zero is NULL, as in MySQL.
+        '/' | '%' if right == 0.0 => return None,
+        '/' => left / right,
+        _ => left % right,
+    };
+    Some(numeric::format_f64(result))
+}
+
+fn call(name: &str, args: &[Expr], row: &[String]) -> Option<String> {
+    match name {
+        "COALESCE" | "IFNULL" => args.iter().find_map(|arg| evaluate(arg, row)),
+        "CONCAT_WS" => {
+            let separator = evaluate(&args[0], row)?;
+            let parts: Vec<String> = args[1..].iter().filter_map(|arg| evaluate(arg, row)).collect();
+            Some(parts.join(&separator))
+        }
+        _ => {
+            let values: Vec<String> = args
+                .iter()
+                .map(|arg| evaluate(arg, row))
+                .collect::<Option<Vec<String>>>()?;
+            call_strict(name, &values)
+        }
+    }
+}
+
+fn call_strict(name: &str, values: &[String]) -> Option<String> {
+    match name {
+        "CONCAT" => Some(values.concat()),
+        "LOWER" => Some(values[0].to_lowercase()),
+        "UPPER" => Some(values[0].to_uppercase()),
+        "TRIM" => Some(values[0].trim().to_string()),
+        "LENGTH" => Some(values[0].len().to_string()),
+        "SUBSTRING" | "SUBSTR" => {
+            let length = values.get(2).map(|length| to_number(length) as i64);
+            Some(substring(&values[0], to_number(&values[1]) as i64, length))
+        }
+        "LEFT" => Some(values[0].chars().take(to_number(&values[1]).max(0.0) as usize).collect()),
+        "RIGHT" => {
+            let count = values[0].chars().count();
+            let take = (to_number(&values[1]).max(0.0) as usize).min(count);
+            Some(values[0].chars().skip(count - take).collect())
+        }
+        "REPLACE" if values[1].is_empty() => Some(values[0].clone()),
+        "REPLACE" => Some(values[0].replace(&values[1], &values[2])),
+        "ROUND" => {
+            let digits = values.get(1).map_or(0, |digits| to_number(digits) as i32);
+            if let (Some((negative, whole, fraction)), Ok(scale)) = (cast::parse_decimal(values[0].trim()), usize::try_from(digits)) {
+                let (whole, fraction) = cast::round_decimal(&whole, &fraction, Some(scale));
+                let zero = whole.bytes().chain(fraction.bytes()).all(|b| b == b'0');
+                let sign = if negative && !zero { "-" } else { "" };
+                return Some(match fraction.as_str() {
+                    "" => format!("{}{}", sign, whole),
+                    _ => format!("{}{}.{}", sign, whole, fraction),
+                });
+            }
+            let scale = 10f64.powi(digits);
+            let rounded = (to_number(&values[0]) * scale).round() / scale;
+            Some(if digits > 0 {
+                format!("{:.*}", digits as usize, rounded)
+            } else {
+                numeric::format_f64(rounded)
+            })
+        }
+        "DATE_FORMAT" => date_format(&values[0], &values[1]),
+        _ => unreachable!("unknown function {}", name),
+    }
+}
+
+/// `SUBSTRING` with MySQL's 1-based and negative positions.
+fn substring(value: &str, position: i64, length: Option<i64>) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    let count = chars.len() as i64;
+    let start = match position {
+        0 => return String::new(),
+        position if position > 0 => position - 1,
+        position => count + position,
+    };
+    if start < 0 || start >= count {
+        return String::new();
+    }
+    let end = match length {
+        Some(length) if length <= 0 => return String::new(),
+        Some(length) => (start + length).min(count),
+        None => count,
+    };
+    chars[start as usize..end as usize].iter().collect()
+}
+
+/// `DATE_FORMAT` with MySQL's format specifiers, on exported date values.
+fn date_format(value: &str, format: &str) -> Option<String> {
+    let date = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f")
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S"))
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y-%m-%d").map(|date| date.and_hms_opt(0, 0, 0).unwrap()))
+        .ok()?;
+    let mut result = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        let specifier = match chars.next() {
+            Some(specifier) => specifier,
+            None => break,
+        };
+        let chrono_format = match specifier {
+            'Y' => "%Y",
+            'y' => "%y",
+            'm' => "%m",
+            'c' => "%-m",
+            'd' => "%d",
+            'e' => "%-d",
+            'H' => "%H",
+            'k' => "%-H",
+            'h' | 'I' => "%I",
+            'l' => "%-I",
+            'i' => "%M",
+            's' | 'S' => "%S",
+            'f' => "%6f",
+            'p' => "%p",
+            'M' => "%B",
+            'b' => "%b",
+            'W' => "%A",
+            'a' => "%a",
+            'j' => "%j",
+            'T' => "%H:%M:%S",
+            'r' => "%I:%M:%S %p",
+            other => {
+                result.push(other);
+                continue;
+            }
+        };
+        result.push_str(&date.format(chrono_format).to_string());
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns() -> Vec<String> {
+        vec!["a".to_string(), "b".to_string()]
+    }
+
+    fn eval(source: &str, row: &[&str]) -> Option<String> {
+        let row: Vec<String> = row.iter().map(|value| value.to_string()).collect();
+        evaluate(&parse(source, &columns()).unwrap(), &row)
+    }
+
+    #[test]
+    fn follows_precedence() {
+        assert_eq!(eval("1 + 2 * 3", &[]), Some("7".to_string()));
+        assert_eq!(eval("(1 + 2) * 3", &[]), Some("9".to_string()));
+        assert_eq!(eval("10 - 2 - 3", &[]), Some("5".to_string()));
+        assert_eq!(eval("-2 * 3 + 7 % 4", &[]), Some("-3".to_string()));
+        assert_eq!(eval("a * b + 1", &["4", "5"]), Some("21".to_string()));
+        // Division keeps four more decimals than the dividend, as in MySQL.
+        assert_eq!(eval("7 / 2", &[]), Some("3.5000".to_string()));
+    }
+
+    #[test]
+    fn keeps_decimals_exact() {
+        // 0.1 and 0.2 have no exact float representation.
+        assert_eq!(eval("a + b", &["0.1", "0.2"]), Some("0.3".to_string()));
+        let price = "1234567890123456.7891";
+        assert_eq!(eval("a * 1.1", &[price, "0"]), Some("1358024679135802.46801".to_string()));
+        assert_eq!(eval("a - b", &["99999999999999999999.99", "0.01"]), Some("99999999999999999999.98".to_string()));
+        assert_eq!(eval("a % b", &["-7.5", "2"]), Some("-1.5".to_string()));
+        assert_eq!(eval("-a", &["0.10", "0"]), Some("-0.10".to_string()));
+        assert_eq!(eval("a / 3", &["1.00", "0"]), Some("0.333333".to_string()));
+        assert_eq!(eval("a / 3", &["-2", "0"]), Some("-0.6667".to_string()));
+        assert_eq!(eval("ROUND(a, 2)", &["1234567890123456.785", "0"]), Some("1234567890123456.79".to_string()));
+        assert_eq!(eval("ROUND(a)", &["-0.4", "0"]), Some("0".to_string()));
+        // Text falls back to floats, counting from its leading number.
+        assert_eq!(eval("a + 1", &["2.5 kg", "0"]), Some("3.5".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "too large for exact arithmetic")]
+    fn stops_when_digits_would_be_lost() {
+        let big = "9".repeat(30);
+        eval("a * b", &[&big, &big]);
+    }
+
+    #[test]
+    fn propagates_null() {
+        assert_eq!(eval("a + 1", &["NULL", "1"]), None);
+        assert_eq!(eval("NULL * 2", &[]), None);
+        assert_eq!(eval("CONCAT(a, b)", &["x", "NULL"]), None);
+        assert_eq!(eval("COALESCE(a, b, 'z')", &["NULL", "NULL"]), Some("z".to_string()));
+        assert_eq!(eval("IFNULL(a, b)", &["NULL", "y"]), Some("y".to_string()));
+        assert_eq!(eval("CONCAT_WS('-', a, b)", &["x", "NULL"]), Some("x".to_string()));
+        assert_eq!(eval("a / 0", &["1", "1"]), None);
+        assert_eq!(eval("a % 0", &["1", "1"]), None);
+    }
+
+    #[test]
+    fn does_not_overflow() {
+        assert_eq!(eval("a % -1", &["-9223372036854775808", "1"]), Some("0".to_string()));
+        assert_eq!(eval("a + 1", &["9223372036854775807", "1"]), Some("9223372036854775808".to_string()));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        assert_eq!(parse("c + 1", &columns()).unwrap_err(), "column c is not exported");
+        assert_eq!(parse("NOW()", &columns()).unwrap_err(), "unknown function NOW");
+        assert_eq!(parse("LOWER(a, b)", &columns()).unwrap_err(), "wrong number of arguments to LOWER");
+        assert!(parse("(a + 1", &columns()).is_err());
+        assert!(parse("a b", &columns()).is_err());
+    }
+}
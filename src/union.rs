@@ -0,0 +1,60 @@
+use serde_json::json;
+use crate::connection::Discriminator;
+use crate::database::Database;
+use crate::discovery::{self, TablePattern};
+use crate::schema;
+use crate::tables::TableConfig;
+
+/// Replaces every table with a `union` by one table per matching source
+/// table, each exported into the union's table.
+///
+/// A member copies the union's settings. When the union lists `columns`,
+/// a member exports those it has, and the destination fills in the rest
+/// with their defaults.
+pub fn expand(database: &Database, table_config: Vec<TableConfig>) -> Vec<TableConfig> {
+    if table_config.iter().all(|table| table.union.is_none()) {
+        return table_config;
+    }
+    let base_tables = discovery::query_base_tables(database);
+    let mut expanded: Vec<TableConfig> = Vec::new();
+    for table in table_config {
+        let patterns: Vec<TablePattern> = match &table.union {
+            Some(patterns) => patterns
+                .iter()
+                .map(|pattern| TablePattern::parse(pattern).unwrap_or_else(|err| panic!("{}: {}", table.name, err)))
+                .collect(),
+            None => {
+                expanded.push(table);
+                continue;
+            }
+        };
+        let members: Vec<&String> = base_tables
+            .iter()
+            .filter(|name| patterns.iter().any(|pattern| pattern.matches(name)))
+            .collect();
+        if members.is_empty() {
+            panic!("{}: no source table matches the union", table.name);
+        }
+        println!("Union of {} tables into {}", members.len(), table.name);
+        for member in members {
+            let mut config = table.clone();
+            config.name = member.clone();
+            config.table_rename = Some(table.name.clone());
+            config.union = None;
+            config.origin_column = None;
+            config.union_member = true;
+            if let Some(columns) = table.columns.as_ref().filter(|cols| !(cols.len() == 1 && cols[0] == "*")) {
+                let column_info = schema::get_column_info(database, member);
+                config.columns = Some(columns.iter().filter(|col| column_info.contains_key(*col)).cloned().collect());
+            }
+            if let Some(origin_column) = &table.origin_column {
+                config.discriminators.push(Discriminator {
+                    column: origin_column.clone(),
+                    value: json!(member),
+                });
+            }
+            expanded.push(config);
+        }
+    }
+    expanded
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use mysql::prelude::Queryable;
+use mysql::Value as MySqlValue;
+use crate::charset::ColumnCharset;
+use crate::column_mapping;
+use crate::database::Database;
+use crate::mysql_utils::{quote_string, value_to_string};
+use crate::schema::{self, ColumnKind};
+use crate::table_export;
+use crate::tables::TableConfig;
+
+/// How many mismatches are printed per table before only counting them.
+const MISMATCH_DETAIL_LIMIT: usize = 10;
+/// Source rows compared at a time, so memory stays bounded on large tables.
+const CHUNK_ROWS: usize = 10000;
+
+/// A row's primary key values, their text used to match rows, and its
+/// numeric values.
+type NumericRow = (Vec<MySqlValue>, String, Vec<MySqlValue>);
+
+/// Compares numeric values bit-for-bit: floats by their IEEE 754 bits,
+/// DECIMAL by the exact digits MySQL returns and integers by value.
+fn same_bits(source: &MySqlValue, destination: &MySqlValue) -> bool {
+    match (source, destination) {
+        (MySqlValue::Float(a), MySqlValue::Float(b)) => a.to_bits() == b.to_bits(),
+        (MySqlValue::Double(a), MySqlValue::Double(b)) => a.to_bits() == b.to_bits(),
+        (MySqlValue::Int(a), MySqlValue::UInt(b)) | (MySqlValue::UInt(b), MySqlValue::Int(a)) => {
+            *a as i128 == *b as i128
+        }
+        (a, b) => a == b,
+    }
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("`{}`", name.replace('`', "``"))
+}
+
+/// Reads the primary key and numeric columns of the rows whose key is
+/// after `after` and up to `until`, ordered by the key, at most `limit`.
+#[allow(clippy::too_many_arguments)]
+fn query_numeric_rows(
+    database: &Database,
+    table: &str,
+    key_columns: &[String],
+    numeric_columns: &[String],
+    condition: &Option<String>,
+    after: &Option<Vec<MySqlValue>>,
+    until: Option<&[MySqlValue]>,
+    limit: Option<usize>,
+) -> Result<Vec<NumericRow>, mysql::Error> {
+    let selected: Vec<String> = key_columns
+        .iter()
+        .chain(numeric_columns)
+        .map(|column| quote_identifier(column))
+        .collect();
+    let keys: Vec<String> = key_columns.iter().map(|column| quote_identifier(column)).collect();
+    let placeholders = vec!["?"; key_columns.len()].join(", ");
+    let mut conditions: Vec<String> = Vec::new();
+    let mut params: Vec<MySqlValue> = Vec::new();
+    if let Some(cond) = condition {
+        conditions.push(format!("({})", cond));
+    }
+    if let Some(after) = after {
+        conditions.push(format!("({}) > ({})", keys.join(", "), placeholders));
+        params.extend(after.iter().cloned());
+    }
+    if let Some(until) = until {
+        conditions.push(format!("({}) <= ({})", keys.join(", "), placeholders));
+        params.extend(until.iter().cloned());
+    }
+    let mut query = format!("SELECT {} FROM {}", selected.join(", "), quote_identifier(table));
+    if !conditions.is_empty() {
+        query.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+    }
+    query.push_str(&format!(" ORDER BY {}", keys.join(", ")));
+    if let Some(limit) = limit {
+        query.push_str(&format!(" LIMIT {}", limit));
+    }
+    let mut conn = database.pool.get_conn()?;
+    let rows: Vec<mysql::Row> = conn.exec(query, params)?;
+    let charset = ColumnCharset::default();
+    Ok(rows.into_iter().map(|row| {
+        let mut values = row.unwrap();
+        let numeric = values.split_off(key_columns.len());
+        let key = values
+            .iter()
+            .map(|value| value_to_string(value.clone(), &charset))
+            .collect::<Vec<String>>()
+            .join(", ");
+        (values, key, numeric)
+    }).collect())
+}
+
+/// Limits the destination to the rows of the table's source, and of its
+/// source table for a `union`.
+fn discriminator_condition(table: &TableConfig) -> Option<String> {
+    if table.discriminators.is_empty() {
+        return None;
+    }
+    let conditions: Vec<String> = table.discriminators.iter().map(|discriminator| {
+        let column = quote_identifier(&discriminator.column);
+        match &discriminator.value {
+            serde_json::Value::Null => format!("{} IS NULL", column),
+            serde_json::Value::String(value) => format!("{} = {}", column, quote_string(value)),
+            value => format!("{} = {}", column, value),
+        }
+    }).collect();
+    Some(conditions.join(" AND "))
+}
+
+/// Compares every exported numeric column between source and destination
+/// after import, matching rows by primary key. Rows are read in chunks of
+/// `CHUNK_ROWS` source rows ordered by the key, along with the destination
+/// rows in the same key range.
+///
+/// Overrides that change numeric values show up as mismatches.
+pub fn verify_numeric(
+    source: &Database,
+    destination: &Database,
+    table: &TableConfig,
+) {
+    let columns: Vec<String> = table_export::get_columns(source, table);
+    let column_info = schema::get_column_info(source, &table.name);
+    let kinds = schema::get_column_kinds(&columns, &column_info);
+    let key_columns: Vec<String> = columns
+        .iter()
+        .filter(|column| column_info.get(*column).is_some_and(|info| info.is_primary))
+        .cloned()
+        .collect();
+    let numeric_columns: Vec<String> = columns
+        .iter()
+        .zip(&kinds)
+        .filter(|(column, kind)| **kind == ColumnKind::Numeric && !key_columns.contains(column))
+        .map(|(column, _)| column.clone())
+        .collect();
+    if table.outputs.is_some() {
+        println!("Skipped verifying {}: split into several tables", table.name);
+        return;
+    }
+    if key_columns.is_empty() {
+        println!("Skipped verifying {}: no primary key among exported columns", table.name);
+        return;
+    }
+    if numeric_columns.is_empty() {
+        return;
+    }
+    let destination_columns = column_mapping::get_destination_columns(table, &columns, &table.column_rename);
+    let rename = |column: &String| -> String {
+        destination_columns[columns.iter().position(|col| col == column).unwrap()].clone()
+    };
+    let destination_table = table.table_rename.as_ref().unwrap_or(&table.name);
+    let destination_keys: Vec<String> = key_columns.iter().map(rename).collect();
+    let destination_numeric: Vec<String> = numeric_columns.iter().map(rename).collect();
+
+    let destination_condition = discriminator_condition(table);
+
+    let mut verified = 0;
+    let mut missing = 0;
+    let mut mismatches = 0;
+    let mut last_key: Option<Vec<MySqlValue>> = None;
+    loop {
+        let source_rows = query_numeric_rows(
+            source,
+            &table.name,
+            &key_columns,
+            &numeric_columns,
+            &table.condition,
+            &last_key,
+            None,
+            Some(CHUNK_ROWS)
+        );
+        let source_rows = match source_rows {
+            Ok(rows) if rows.is_empty() => break,
+            Ok(rows) => rows,
+            Err(err) => {
+                eprintln!("Error verifying {}: {}", table.name, err);
+                return;
+            }
+        };
+        let chunk_end: Vec<MySqlValue> = source_rows.last().unwrap().0.clone();
+        let destination_rows = query_numeric_rows(
+            destination,
+            destination_table,
+            &destination_keys,
+            &destination_numeric,
+            &destination_condition,
+            &last_key,
+            Some(&chunk_end),
+            None
+        );
+        let destination_rows: HashMap<String, Vec<MySqlValue>> = match destination_rows {
+            Ok(rows) => rows.into_iter().map(|(_, key, numeric)| (key, numeric)).collect(),
+            Err(err) => {
+                eprintln!("Error verifying {}: {}", table.name, err);
+                return;
+            }
+        };
+        for (_, key, source_values) in &source_rows {
+            let destination_values = match destination_rows.get(key) {
+                Some(values) => values,
+                None => {
+                    missing += 1;
+                    continue;
+                }
+            };
+            for (pos, (source_value, destination_value)) in source_values.iter().zip(destination_values).enumerate() {
+                if !same_bits(source_value, destination_value) {
+                    if mismatches < MISMATCH_DETAIL_LIMIT {
+                        eprintln!(
+                            "{}.{} [{}]: source {:?} differs from destination {:?}",
+                            table.name, numeric_columns[pos], key, source_value, destination_value
+                        );
+                    }
+                    mismatches += 1;
+                }
+            }
+        }
+        verified += source_rows.len();
+        if source_rows.len() < CHUNK_ROWS {
+            break;
+        }
+        last_key = Some(chunk_end);
+    }
+    println!(
+        "Verified {} numeric columns of {} rows in {} with {} mismatches and {} missing rows",
+        numeric_columns.len(), verified, table.name, mismatches, missing
+    );
+}